@@ -0,0 +1,144 @@
+// Throughput of the store with many bots writing at once.
+// 20 accounts each get their own writer and reader task. Same load is run twice: once with
+// accounts locked on their own and once with every commit behind one lock, which is how the
+// database used to be locked. Every commit goes through the journal like in the server, with
+// fsync when `fsync` is passed.
+//
+//     cargo run --release --example store_bench -- [events per account] [fsync]
+use shopee_logs_collector::journal;
+use shopee_logs_collector::models::{blank_db, commit, Db, Event, Log, UpdateStat};
+use shopee_logs_collector::severity::Severity;
+use shopee_logs_collector::timestamp::Timestamp;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::RwLock;
+
+const ACCOUNTS: usize = 20;
+const DEFAULT_EVENTS: usize = 20_000;
+
+fn event(account: &str, i: usize) -> Event {
+    if i.is_multiple_of(4) {
+        return Event::UpdateStats {
+            account: account.to_owned(),
+            input: UpdateStat {
+                error_counts: None,
+                running: Some(true),
+                no_of_api_call_diff: Some(1),
+            },
+        };
+    }
+
+    Event::AddLog {
+        account: account.to_owned(),
+        input: Log {
+            r#type: if i.is_multiple_of(10) {
+                Severity::Error
+            } else {
+                Severity::Info
+            },
+            time: None,
+            received_at: Timestamp::now(),
+            message: format!("Bid updated for keyword {}", i % 50),
+            meta: None,
+            seq: 0,
+        },
+    }
+}
+
+// `global` is taken around every access when set, like the old single lock database
+async fn run(db: Db, global: Option<Arc<RwLock<()>>>, events: usize) -> (f64, usize) {
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+
+    for n in 0..ACCOUNTS {
+        let account = format!("account-{}", n);
+        let slot = db.get_or_create(&account);
+        let done = Arc::new(AtomicBool::new(false));
+
+        let (slot_w, global_w, done_w) = (slot.clone(), global.clone(), done.clone());
+        tasks.push(tokio::spawn(async move {
+            for i in 0..events {
+                let global_lock = match &global_w {
+                    Some(global) => Some(global.write().await),
+                    None => None,
+                };
+                let mut statistics = slot_w.write().await;
+                commit(&mut statistics, event(&account, i))
+                    .await
+                    .expect("Error on commit");
+                drop(global_lock);
+            }
+            done_w.store(true, Ordering::SeqCst);
+            0
+        }));
+
+        // Reads stats like the dashboard does until writer of the account is done
+        let global_r = global.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut reads = 0;
+            while !done.load(Ordering::SeqCst) {
+                {
+                    let _global_lock = match &global_r {
+                        Some(global) => Some(global.read().await),
+                        None => None,
+                    };
+                    let statistics = slot.read().await;
+                    let _ = statistics.main_stats.logs.len();
+                }
+
+                reads += 1;
+                let () = tokio::task::yield_now().await;
+            }
+            reads
+        }));
+    }
+
+    let mut reads = 0;
+    for task in tasks {
+        reads += task.await.unwrap_or(0);
+    }
+
+    (start.elapsed().as_secs_f64(), reads)
+}
+
+fn report(name: &str, (secs, reads): (f64, usize), events: usize) {
+    let writes = ACCOUNTS * events;
+    println!(
+        "{:<12} {:>8.3}s {:>12.0} writes/s {:>12.0} reads/s",
+        name,
+        secs,
+        writes as f64 / secs,
+        reads as f64 / secs
+    );
+}
+
+fn main() {
+    let events = std::env::args()
+        .nth(1)
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_EVENTS);
+
+    let fsync = std::env::args().nth(2).as_deref() == Some("fsync");
+
+    // Journal is left behind by earlier runs otherwise and replayed on init
+    let dir = std::env::temp_dir().join("store_bench");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("Error on journal directory");
+    journal::init(&dir, fsync).expect("Error on journal");
+
+    let mut rt = tokio::runtime::Runtime::new().expect("Error on tokio runtime");
+
+    println!(
+        "{} accounts, {} events per account, fsync {}",
+        ACCOUNTS, events, fsync
+    );
+
+    let per_account = rt.block_on(run(blank_db(), None, events));
+    report("per account", per_account, events);
+
+    let global = rt.block_on(run(blank_db(), Some(Arc::new(RwLock::new(()))), events));
+    report("global lock", global, events);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,764 @@
+// Alerting.
+// Rules come from `[alerts]` in config.toml and are checked after every committed event for its
+// account, and every `check_interval_secs` for all accounts so time based rules fire without updates.
+//
+// A rule fires once when its condition becomes true and sends a `resolved` notification when it
+// stops being true. Each rule is tracked per account, keyword rules per keyword as well.
+// Notifications are posted as json to webhooks and retried with backoff when delivery fails.
+// A notification which got through but whose response was lost is sent again, so every post has
+// an `Idempotency-Key` header which is the same for all attempts of one notification. Receivers
+// should drop notifications whose key they have already seen.
+// While a webhook is down at most `MAX_QUEUED` notifications wait for it, oldest are dropped.
+//
+// Firing alerts are only kept in memory so after restart they fire again if still true.
+use crate::events;
+use crate::liveness;
+use crate::metrics;
+use crate::models::{Db, Event, KeywordId, Statistics};
+use crate::timestamp::Timestamp;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::time::Duration;
+use tracing::{error, info, warn};
+
+const DEFAULT_RETRIES: u32 = 5;
+const DEFAULT_CHECK_INTERVAL_SECS: u64 = 30;
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+const MAX_BACKOFF_SECS: u64 = 60;
+const MAX_QUEUED: usize = 1000;
+
+static ALERTS: OnceCell<Alerts> = OnceCell::new();
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct AlertsConfig {
+    // How often time based rules (error rate, stale) are checked without updates
+    pub check_interval_secs: Option<u64>,
+
+    // Failed deliveries are retried this many times
+    pub retries: Option<u32>,
+    pub timeout_secs: Option<u64>,
+
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Webhook {
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Rule {
+    pub name: String,
+
+    // Accounts rule applies to. `*` means every account.
+    #[serde(default = "all_accounts")]
+    pub accounts: Vec<String>,
+
+    // Names of webhooks to notify. Every webhook if empty.
+    #[serde(default)]
+    pub webhooks: Vec<String>,
+
+    #[serde(flatten)]
+    pub condition: Condition,
+}
+
+fn all_accounts() -> Vec<String> {
+    vec!["*".to_owned()]
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Condition {
+    // Compares a field of `MainStats` or of every `KeywordStat` against value
+    Field {
+        target: Target,
+        field: String,
+        op: Op,
+        value: Value,
+    },
+    // More than max_errors error logs (or reported errors) in last window_secs
+    ErrorRate { max_errors: u64, window_secs: u64 },
+    // Account got no update or heartbeat for after_secs
+    Stale { after_secs: u64 },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    Main,
+    Keyword,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum Op {
+    #[serde(rename = "==")]
+    Eq,
+    #[serde(rename = "!=")]
+    Ne,
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = ">=")]
+    Ge,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+}
+
+impl Op {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Op::Eq => "==",
+            Op::Ne => "!=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+        }
+    }
+
+    // Numbers are compared as numbers so `1` equals `1.0`. Ordering of anything else is false.
+    fn matches(&self, observed: &Value, expected: &Value) -> bool {
+        if let (Some(a), Some(b)) = (observed.as_f64(), expected.as_f64()) {
+            return match self {
+                Op::Eq => (a - b).abs() < f64::EPSILON,
+                Op::Ne => (a - b).abs() >= f64::EPSILON,
+                Op::Gt => a > b,
+                Op::Ge => a >= b,
+                Op::Lt => a < b,
+                Op::Le => a <= b,
+            };
+        }
+
+        match self {
+            Op::Eq => observed == expected,
+            Op::Ne => observed != expected,
+            _ => false,
+        }
+    }
+}
+
+impl Rule {
+    fn applies_to(&self, account: &str) -> bool {
+        self.accounts.iter().any(|a| a == "*" || a == account)
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Firing,
+    Resolved,
+}
+
+impl Status {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Status::Firing => "firing",
+            Status::Resolved => "resolved",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Notification<'a> {
+    status: Status,
+    // Same for firing and resolved notification of one alert
+    key: &'a str,
+    rule: &'a str,
+    account: &'a str,
+    keyword_id: Option<KeywordId>,
+    message: String,
+    value: Value,
+    started_at: Timestamp,
+    resolved_at: Option<Timestamp>,
+}
+
+#[derive(Default)]
+struct State {
+    // Firing alerts by key and when they started
+    active: HashMap<String, Timestamp>,
+
+    // Errors seen per account, oldest first. Only kept as long as longest error_rate window.
+    errors: HashMap<String, VecDeque<(Timestamp, u64)>>,
+}
+
+impl State {
+    fn record_errors(&mut self, account: &str, count: u64, time: Timestamp, window_secs: u64) {
+        let errors = self.errors.entry(account.to_owned()).or_default();
+        if count > 0 {
+            errors.push_back((time, count));
+        }
+
+        let cutoff = time.add_secs(-(window_secs as i64));
+        while errors.front().is_some_and(|(t, _)| *t < cutoff) {
+            errors.pop_front();
+        }
+    }
+
+    fn errors_since(&self, account: &str, since: Timestamp) -> u64 {
+        self.errors
+            .get(account)
+            .map(|errors| {
+                errors
+                    .iter()
+                    .filter(|(t, _)| *t >= since)
+                    .map(|(_, count)| count)
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+}
+
+// Serialized notification and its idempotency key
+#[derive(Debug, Clone)]
+struct Delivery {
+    id: String,
+    body: String,
+}
+
+// Notifications waiting for one webhook, oldest first
+struct Queue {
+    name: String,
+    items: Mutex<VecDeque<Delivery>>,
+    ready: Notify,
+}
+
+impl Queue {
+    fn new(name: &str) -> Queue {
+        Queue {
+            name: name.to_owned(),
+            items: Mutex::new(VecDeque::new()),
+            ready: Notify::new(),
+        }
+    }
+
+    fn push(&self, delivery: Delivery) {
+        let mut items = self.items.lock().unwrap();
+        items.push_back(delivery);
+
+        if items.len() > MAX_QUEUED {
+            if let Some(dropped) = items.pop_front() {
+                warn!(
+                    "Alert queue of {} is full, dropped notification {}",
+                    self.name, dropped.id
+                );
+            }
+        }
+
+        drop(items);
+        self.ready.notify();
+    }
+
+    async fn pop(&self) -> Delivery {
+        loop {
+            let next = self.items.lock().unwrap().pop_front();
+            if let Some(delivery) = next {
+                return delivery;
+            }
+
+            self.ready.notified().await;
+        }
+    }
+}
+
+struct Alerts {
+    rules: Vec<Rule>,
+    webhooks: Vec<Arc<Queue>>,
+    // Longest error_rate window, 0 if there is no such rule
+    error_window_secs: u64,
+    state: Mutex<State>,
+}
+
+// Queues of webhooks, consumed by `run`
+pub struct Deliveries {
+    config: AlertsConfig,
+    queues: Vec<(Webhook, Arc<Queue>)>,
+}
+
+pub fn init(config: AlertsConfig) -> Deliveries {
+    let mut senders = Vec::new();
+    let mut queues = Vec::new();
+
+    for webhook in config.webhooks.iter() {
+        let queue = Arc::new(Queue::new(&webhook.name));
+        senders.push(queue.clone());
+        queues.push((webhook.clone(), queue));
+    }
+
+    for rule in config.rules.iter() {
+        for name in rule.webhooks.iter() {
+            if !config.webhooks.iter().any(|w| &w.name == name) {
+                warn!("Alert rule {} uses unknown webhook {}", rule.name, name);
+            }
+        }
+    }
+
+    if !config.rules.is_empty() && config.webhooks.is_empty() {
+        warn!("Alert rules are configured but no webhook, alerts are only logged");
+    }
+
+    let error_window_secs = config
+        .rules
+        .iter()
+        .filter_map(|r| match r.condition {
+            Condition::ErrorRate { window_secs, .. } => Some(window_secs),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+
+    let _ = ALERTS.set(Alerts {
+        rules: config.rules.clone(),
+        webhooks: senders,
+        error_window_secs,
+        state: Mutex::new(State::default()),
+    });
+
+    Deliveries { config, queues }
+}
+
+// Called by `commit` after event is applied. `errors_before` is `error_counts` of account before
+// it, so only errors which were reported or stored count, not logs dropped or buffered as orphans.
+pub fn evaluate(statistics: &Statistics, event: &Event, errors_before: u64, time: Timestamp) {
+    let alerts = match ALERTS.get() {
+        Some(alerts) if !alerts.rules.is_empty() => alerts,
+        _ => return,
+    };
+
+    let mut state = alerts.state.lock().unwrap();
+    if alerts.error_window_secs > 0 {
+        let errors = statistics.main_stats.error_counts.saturating_sub(errors_before);
+        state.record_errors(event.account(), errors, time, alerts.error_window_secs);
+    }
+
+    let keywords = events::keyword_ids(event, statistics);
+    alerts.check(&mut state, statistics, &keywords, time);
+}
+
+impl Alerts {
+    fn check(&self, state: &mut State, statistics: &Statistics, keywords: &[KeywordId], time: Timestamp) {
+        let account = statistics.main_stats.account_name.clone();
+        let mut main_fields = None;
+
+        for rule in self.rules.iter().filter(|r| r.applies_to(&account)) {
+            match &rule.condition {
+                Condition::Field {
+                    target: Target::Main,
+                    field,
+                    op,
+                    value,
+                } => {
+                    let fields = main_fields
+                        .get_or_insert_with(|| events::main_stats_fields(&statistics.main_stats));
+                    let observed = fields.get(field).cloned().unwrap_or(Value::Null);
+                    let message = format!("{} of {} is {} ({} {})", field, account, observed, op.as_str(), value);
+                    let firing = op.matches(&observed, value);
+                    self.transition(state, rule, &account, None, firing, observed, message, time);
+                }
+                Condition::Field {
+                    target: Target::Keyword,
+                    field,
+                    op,
+                    value,
+                } => {
+                    for id in keywords {
+                        let ks = match statistics.keyword_stats.get(id) {
+                            Some(ks) => ks,
+                            None => continue,
+                        };
+
+                        let observed = events::to_fields(&ks.stats)
+                            .remove(field)
+                            .unwrap_or(Value::Null);
+                        let message = format!(
+                            "{} of keyword {} in {} is {} ({} {})",
+                            field, id, account, observed, op.as_str(), value
+                        );
+                        let firing = op.matches(&observed, value);
+                        self.transition(state, rule, &account, Some(*id), firing, observed, message, time);
+                    }
+                }
+                Condition::ErrorRate {
+                    max_errors,
+                    window_secs,
+                } => {
+                    let count = state.errors_since(&account, time.add_secs(-(*window_secs as i64)));
+                    let message = format!(
+                        "{} had {} errors in last {}s (max {})",
+                        account, count, window_secs, max_errors
+                    );
+                    self.transition(state, rule, &account, None, count > *max_errors, json!(count), message, time);
+                }
+                Condition::Stale { after_secs } => {
+                    let idle = time.secs_since(liveness::main_last_seen(&statistics.main_stats));
+                    let message = format!("{} got no update for {}s", account, idle);
+                    let firing = idle > *after_secs as i64;
+                    self.transition(state, rule, &account, None, firing, json!(idle), message, time);
+                }
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn transition(
+        &self,
+        state: &mut State,
+        rule: &Rule,
+        account: &str,
+        keyword_id: Option<KeywordId>,
+        firing: bool,
+        value: Value,
+        message: String,
+        time: Timestamp,
+    ) {
+        let key = match keyword_id {
+            Some(id) => format!("{}/{}/{}", rule.name, account, id),
+            None => format!("{}/{}", rule.name, account),
+        };
+
+        let (status, started_at, resolved_at) = match (firing, state.active.get(&key).copied()) {
+            (true, None) => {
+                state.active.insert(key.clone(), time);
+                (Status::Firing, time, None)
+            }
+            (false, Some(started_at)) => {
+                state.active.remove(&key);
+                (Status::Resolved, started_at, Some(time))
+            }
+            _ => return,
+        };
+
+        info!("Alert {} {:?}: {}", key, status, message);
+
+        let notification = Notification {
+            status,
+            key: &key,
+            rule: &rule.name,
+            account,
+            keyword_id,
+            message,
+            value,
+            started_at,
+            resolved_at,
+        };
+
+        let body = match serde_json::to_string(&notification) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Unable to serialize alert {}: {:?}", key, e);
+                return;
+            }
+        };
+
+        let delivery = Delivery {
+            id: format!("{}@{}/{}", key, started_at.epoch_millis(), status.as_str()),
+            body,
+        };
+
+        for queue in self.webhooks.iter() {
+            if rule.webhooks.is_empty() || rule.webhooks.contains(&queue.name) {
+                queue.push(delivery.clone());
+            }
+        }
+    }
+}
+
+// Checks every account so time based rules fire and resolve without updates
+async fn check_periodically(db: Db, interval: Duration) {
+    let alerts = match ALERTS.get() {
+        Some(alerts) if !alerts.rules.is_empty() => alerts,
+        _ => return,
+    };
+
+    loop {
+        tokio::time::delay_for(interval).await;
+
+        // Read lock is enough, what fired is kept in `alerts.state`
+        for (account, slot) in db.all() {
+            let statistics = metrics::read_account(&slot).await;
+            let now = Timestamp::now();
+            let mut state = alerts.state.lock().unwrap();
+
+            if alerts.error_window_secs > 0 {
+                state.record_errors(&account, 0, now, alerts.error_window_secs);
+            }
+
+            let keywords: Vec<KeywordId> = statistics.keyword_stats.keys().copied().collect();
+            alerts.check(&mut state, &statistics, &keywords, now);
+        }
+    }
+}
+
+type HttpsClient = Client<HttpsConnector<HttpConnector>, Body>;
+
+async fn post(
+    client: &HttpsClient,
+    url: &str,
+    delivery: &Delivery,
+    timeout: Duration,
+) -> Result<(), String> {
+    let request = Request::post(url)
+        .header("content-type", "application/json")
+        .header("idempotency-key", delivery.id.as_str())
+        .body(Body::from(delivery.body.clone()))
+        .map_err(|e| e.to_string())?;
+
+    match tokio::time::timeout(timeout, client.request(request)).await {
+        Ok(Ok(response)) if response.status().is_success() => Ok(()),
+        Ok(Ok(response)) => Err(format!("status {}", response.status())),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".to_owned()),
+    }
+}
+
+// Delivers notifications of one webhook in order, retrying each with exponential backoff
+// starting at `backoff`
+async fn deliver(
+    client: HttpsClient,
+    webhook: Webhook,
+    queue: Arc<Queue>,
+    retries: u32,
+    timeout: Duration,
+    backoff: Duration,
+) {
+    loop {
+        let delivery = queue.pop().await;
+        let mut attempt = 0;
+
+        loop {
+            match post(&client, &webhook.url, &delivery, timeout).await {
+                Ok(()) => break,
+                Err(e) if attempt < retries => {
+                    let delay = (backoff * (1 << attempt.min(6)))
+                        .min(Duration::from_secs(MAX_BACKOFF_SECS));
+                    warn!(
+                        "Alert delivery to {} failed ({}), retrying in {:?}",
+                        webhook.name, e, delay
+                    );
+                    tokio::time::delay_for(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    error!(
+                        "Giving up alert delivery to {} after {} attempts: {}",
+                        webhook.name,
+                        attempt + 1,
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+    }
+}
+
+pub async fn run(db: Db, deliveries: Deliveries) {
+    let config = deliveries.config;
+    let retries = config.retries.unwrap_or(DEFAULT_RETRIES);
+    let timeout = Duration::from_secs(config.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS));
+    let interval = Duration::from_secs(
+        config
+            .check_interval_secs
+            .unwrap_or(DEFAULT_CHECK_INTERVAL_SECS)
+            .max(1),
+    );
+
+    let client: HttpsClient = Client::builder().build(HttpsConnector::new());
+
+    let backoff = Duration::from_secs(1);
+    let workers = deliveries
+        .queues
+        .into_iter()
+        .map(|(webhook, queue)| deliver(client.clone(), webhook, queue, retries, timeout, backoff));
+
+    futures::join!(
+        check_periodically(db, interval),
+        futures::future::join_all(workers)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server, StatusCode};
+    use std::convert::Infallible;
+    use std::time::Instant;
+
+    // Request seen by the receiver: when, idempotency key and body
+    type Received = Arc<Mutex<Vec<(Instant, String, Value)>>>;
+
+    // Local webhook receiver answering with `statuses` in order, then 200
+    fn receiver(statuses: Vec<u16>) -> (String, Received) {
+        let received: Received = Arc::new(Mutex::new(Vec::new()));
+        let statuses = Arc::new(Mutex::new(VecDeque::from(statuses)));
+
+        let seen = received.clone();
+        let make_service = make_service_fn(move |_| {
+            let seen = seen.clone();
+            let statuses = statuses.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let seen = seen.clone();
+                    let statuses = statuses.clone();
+                    async move {
+                        let at = Instant::now();
+                        let key = request
+                            .headers()
+                            .get("idempotency-key")
+                            .and_then(|v| v.to_str().ok())
+                            .unwrap_or_default()
+                            .to_owned();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        let body = serde_json::from_slice(&body).unwrap();
+                        seen.lock().unwrap().push((at, key, body));
+
+                        let status = statuses.lock().unwrap().pop_front().unwrap_or(200);
+                        let mut response = Response::new(Body::empty());
+                        *response.status_mut() = StatusCode::from_u16(status).unwrap();
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}/hook", server.local_addr());
+        tokio::spawn(server);
+
+        (url, received)
+    }
+
+    fn errors_rule() -> (Alerts, Arc<Queue>) {
+        let queue = Arc::new(Queue::new("test"));
+        let alerts = Alerts {
+            rules: vec![Rule {
+                name: "errors".to_owned(),
+                accounts: all_accounts(),
+                webhooks: Vec::new(),
+                condition: Condition::Field {
+                    target: Target::Main,
+                    field: "error_counts".to_owned(),
+                    op: Op::Gt,
+                    value: json!(0),
+                },
+            }],
+            webhooks: vec![queue.clone()],
+            error_window_secs: 0,
+            state: Mutex::new(State::default()),
+        };
+
+        (alerts, queue)
+    }
+
+    fn delivery(id: &str) -> Delivery {
+        Delivery {
+            id: id.to_owned(),
+            body: json!({ "id": id }).to_string(),
+        }
+    }
+
+    // Runs `deliver` until the receiver got `count` requests
+    async fn deliver_until(url: &str, queue: Arc<Queue>, received: &Received, count: usize) {
+        let client: HttpsClient = Client::builder().build(HttpsConnector::new());
+        let webhook = Webhook {
+            name: "test".to_owned(),
+            url: url.to_owned(),
+        };
+        let timeout = Duration::from_secs(5);
+        let backoff = Duration::from_millis(20);
+        let worker = tokio::spawn(deliver(client, webhook, queue, 5, timeout, backoff));
+
+        let started = Instant::now();
+        while received.lock().unwrap().len() < count {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "receiver got too few requests"
+            );
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+
+        // Anything sent after expected requests would be a duplicate
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        drop(worker);
+    }
+
+    #[tokio::test]
+    async fn fires_once_and_resolves() {
+        let (alerts, queue) = errors_rule();
+        let mut state = State::default();
+        let mut statistics = Statistics::new("shop".to_owned(), Timestamp::now());
+
+        let mut check = |errors: u64| {
+            statistics.main_stats.error_counts = errors;
+            alerts.check(&mut state, &statistics, &[], Timestamp::now());
+        };
+        check(0);
+        check(5);
+        check(7);
+        check(5);
+        check(0);
+        check(0);
+
+        let (url, received) = receiver(Vec::new());
+        deliver_until(&url, queue, &received, 2).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+
+        let (_, firing_key, firing) = &received[0];
+        let (_, resolved_key, resolved) = &received[1];
+        assert_eq!(firing["status"], "firing");
+        assert_eq!(firing["key"], "errors/shop");
+        assert_eq!(firing["value"], 5);
+        assert_eq!(resolved["status"], "resolved");
+        assert_eq!(resolved["key"], "errors/shop");
+        assert_eq!(resolved["started_at"], firing["started_at"]);
+        assert!(!firing_key.is_empty());
+        assert_ne!(firing_key, resolved_key);
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_with_backoff() {
+        let (url, received) = receiver(vec![500, 503]);
+        let queue = Arc::new(Queue::new("test"));
+        queue.push(delivery("a"));
+        queue.push(delivery("b"));
+
+        deliver_until(&url, queue, &received, 4).await;
+
+        let received = received.lock().unwrap();
+        let keys: Vec<&str> = received.iter().map(|(_, key, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["a", "a", "a", "b"]);
+
+        // 20ms, then 40ms
+        assert!(received[1].0 - received[0].0 >= Duration::from_millis(20));
+        assert!(received[2].0 - received[1].0 >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn full_queue_drops_oldest() {
+        let queue = Queue::new("test");
+        for i in 0..=MAX_QUEUED {
+            queue.push(delivery(&i.to_string()));
+        }
+
+        let items = queue.items.lock().unwrap();
+        assert_eq!(items.len(), MAX_QUEUED);
+        assert_eq!(items.front().unwrap().id, "1");
+        assert_eq!(items.back().unwrap().id, MAX_QUEUED.to_string());
+    }
+}
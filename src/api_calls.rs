@@ -0,0 +1,247 @@
+// Api calls made by bots over time.
+// `no_of_api_call_diff` sent to `/{account}/stats` is added to a per minute series besides the
+// running total in `MainStats`. Clearing stats resets the total but not the series.
+// Minutes older than `window_mins` are dropped when new calls are recorded.
+//
+// With a quota configured (`[api_calls]` in config.toml, per account under `accounts.<name>`)
+// accounts which used `warn_ratio` of `max_calls` in last `period_mins` are flagged.
+use crate::timestamp::Timestamp;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+const DEFAULT_WINDOW_MINS: u64 = 24 * 60;
+const DEFAULT_PERIOD_MINS: u64 = 60;
+const DEFAULT_WARN_RATIO: f64 = 0.8;
+
+static CONFIG: OnceCell<ApiCallsConfig> = OnceCell::new();
+static DEFAULT_CONFIG: Lazy<ApiCallsConfig> = Lazy::new(ApiCallsConfig::default);
+
+pub fn init(config: ApiCallsConfig) {
+    let _ = CONFIG.set(config);
+}
+
+fn config() -> &'static ApiCallsConfig {
+    CONFIG.get().unwrap_or(&DEFAULT_CONFIG)
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct Quota {
+    // Calls allowed in period_mins. No quota if not set
+    pub max_calls: Option<u64>,
+    pub period_mins: Option<u64>,
+    // Fraction of max_calls after which account is flagged
+    pub warn_ratio: Option<f64>,
+}
+
+impl Quota {
+    fn or(&self, other: &Quota) -> Quota {
+        Quota {
+            max_calls: self.max_calls.or(other.max_calls),
+            period_mins: self.period_mins.or(other.period_mins),
+            warn_ratio: self.warn_ratio.or(other.warn_ratio),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ApiCallsConfig {
+    // How long per minute counts are kept
+    pub window_mins: Option<u64>,
+
+    #[serde(flatten)]
+    pub quota: Quota,
+
+    #[serde(default)]
+    pub accounts: HashMap<String, Quota>,
+}
+
+impl ApiCallsConfig {
+    fn window_mins(&self) -> u64 {
+        self.window_mins.unwrap_or(DEFAULT_WINDOW_MINS)
+    }
+
+    fn window_secs(&self) -> i64 {
+        (self.window_mins() * 60) as i64
+    }
+
+    fn quota_for(&self, account: &str) -> Quota {
+        match self.accounts.get(account) {
+            Some(quota) => quota.or(&self.quota),
+            None => self.quota.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub struct Minute {
+    pub start: Timestamp,
+    pub calls: u64,
+}
+
+// Oldest first, only minutes with calls are stored
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ApiCallSeries {
+    pub minutes: VecDeque<Minute>,
+}
+
+fn minute_start(time: Timestamp) -> Timestamp {
+    time.floor_millis(60_000)
+}
+
+impl ApiCallSeries {
+    pub fn record(&mut self, calls: u64, time: Timestamp) {
+        let start = minute_start(time);
+
+        match self.minutes.back_mut() {
+            Some(minute) if minute.start == start => minute.calls += calls,
+            _ if calls > 0 => self.minutes.push_back(Minute { start, calls }),
+            _ => {}
+        }
+
+        let cutoff = time.add_secs(-config().window_secs());
+        while self.minutes.front().is_some_and(|m| m.start < cutoff) {
+            self.minutes.pop_front();
+        }
+    }
+
+    pub fn calls_since(&self, since: Timestamp) -> u64 {
+        self.minutes
+            .iter()
+            .rev()
+            .take_while(|m| m.start >= since)
+            .map(|m| m.calls)
+            .sum()
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaStatus {
+    Ok,
+    Warning,
+    Exceeded,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct QuotaUsage {
+    pub max_calls: u64,
+    pub period_mins: u64,
+    pub used: u64,
+    pub ratio: f64,
+    pub status: QuotaStatus,
+}
+
+// None when account has no quota
+pub fn quota_usage(account: &str, series: &ApiCallSeries, now: Timestamp) -> Option<QuotaUsage> {
+    let quota = config().quota_for(account);
+    let max_calls = quota.max_calls?;
+    let period_mins = quota.period_mins.unwrap_or(DEFAULT_PERIOD_MINS);
+
+    // Minute buckets are aligned so the current, partial minute is counted as a whole
+    let used = series.calls_since(minute_start(now.add_secs(-((period_mins * 60) as i64) + 60)));
+    let ratio = if max_calls == 0 {
+        1.0
+    } else {
+        used as f64 / max_calls as f64
+    };
+
+    let status = if used >= max_calls {
+        QuotaStatus::Exceeded
+    } else if ratio >= quota.warn_ratio.unwrap_or(DEFAULT_WARN_RATIO) {
+        QuotaStatus::Warning
+    } else {
+        QuotaStatus::Ok
+    };
+
+    Some(QuotaUsage {
+        max_calls,
+        period_mins,
+        used,
+        ratio,
+        status,
+    })
+}
+
+// `?from=..&to=..&step_mins=..` of `/{account}/api_calls`
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ApiCallsQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    // Minutes merged into one point, 1 by default. At most `window_mins`
+    pub step_mins: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RatePoint {
+    pub start: Timestamp,
+    pub calls: u64,
+    pub per_minute: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiCallsPage {
+    pub account: String,
+    pub step_mins: u64,
+    pub total: u64,
+    // Minutes without calls are left out
+    pub points: Vec<RatePoint>,
+    pub quota: Option<QuotaUsage>,
+}
+
+impl ApiCallsQuery {
+    pub fn run(
+        &self,
+        account: &str,
+        series: &ApiCallSeries,
+        now: Timestamp,
+    ) -> Result<ApiCallsPage, String> {
+        let parse = |value: &Option<String>, name: &str| match value {
+            Some(v) => Timestamp::parse(v)
+                .map(Some)
+                .ok_or_else(|| format!("Invalid {} time {}", name, v)),
+            None => Ok(None),
+        };
+        let from = parse(&self.from, "from")?;
+        let to = parse(&self.to, "to")?;
+
+        // Nothing older than window is kept so a longer step gives nothing more, and capping it
+        // keeps step in millis from overflowing
+        let step_mins = self.step_mins.unwrap_or(1);
+        let max_step_mins = config().window_mins().max(1);
+        if step_mins == 0 || step_mins > max_step_mins {
+            return Err(format!("step_mins must be between 1 and {}", max_step_mins));
+        }
+        let step_ms = (step_mins * 60_000) as i64;
+
+        let mut points: Vec<RatePoint> = Vec::new();
+        for minute in series.minutes.iter() {
+            if from.is_some_and(|f| minute.start < f) || to.is_some_and(|t| minute.start > t) {
+                continue;
+            }
+
+            let start = minute.start.floor_millis(step_ms);
+
+            match points.last_mut() {
+                Some(point) if point.start == start => point.calls += minute.calls,
+                _ => points.push(RatePoint {
+                    start,
+                    calls: minute.calls,
+                    per_minute: 0.0,
+                }),
+            }
+        }
+
+        for point in points.iter_mut() {
+            point.per_minute = point.calls as f64 / step_mins as f64;
+        }
+
+        Ok(ApiCallsPage {
+            account: account.to_owned(),
+            step_mins,
+            total: points.iter().map(|p| p.calls).sum(),
+            points,
+            quota: quota_usage(account, series, now),
+        })
+    }
+}
@@ -0,0 +1,278 @@
+// Logs removed by `clear_db` are written here before they are gone.
+// Every clear creates one gzip compressed json lines file per account:
+//  <archive_dir>/<account>-<hash>/<account>_<utc time>.jsonl.gz
+// Account names are sanitized and shortened, see `helpers::file_stem`.
+// Only the newest `max_files` archives of an account are kept.
+//
+// Removed logs wait in the account's archive queue until their file is written. A failed write
+// leaves them queued and they are retried on next clear or retention sweep.
+// Logs pushed out of full buffers are collected first and archived `OVERFLOW_BATCH` at a time, or
+// by the retention sweep, so they don't make a file each.
+use crate::models::{Drained, KeywordId, Log};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::{error, info};
+
+const ARCHIVE_EXTENSION: &str = ".jsonl.gz";
+
+// Oldest queued batches are dropped past this so a broken archive dir can't eat all memory
+const MAX_QUEUED_LOGS: usize = 50_000;
+
+// Logs pushed out of full buffers are archived once this many have piled up
+pub const OVERFLOW_BATCH: usize = 1000;
+
+static ARCHIVE: OnceCell<Archive> = OnceCell::new();
+
+pub struct Archive {
+    dir: PathBuf,
+    max_files: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArchivedLog<'a> {
+    // None for main logs
+    pub keyword_id: Option<KeywordId>,
+    pub log: &'a Log,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ArchiveFile {
+    pub name: String,
+    pub size: u64,
+}
+
+pub fn init(dir: &Path, max_files: usize) {
+    if let Err(e) = std::fs::create_dir_all(dir) {
+        error!("Unable to create archive directory {:?}: {:?}", dir, e);
+    }
+
+    let archive = Archive {
+        dir: dir.to_owned(),
+        max_files,
+    };
+
+    if ARCHIVE.set(archive).is_err() {
+        error!("Archive was already initialized");
+    }
+}
+
+// Directories were once named by sanitized account name alone. Such a directory is taken over
+// by first account using it.
+fn account_dir(archive: &Archive, account: &str) -> PathBuf {
+    let dir = archive.dir.join(crate::helpers::file_stem(account));
+    let legacy_name = crate::helpers::sanitize(account);
+    let legacy_dir = archive.dir.join(&legacy_name);
+
+    // Empty name would be archive dir itself
+    if !legacy_name.is_empty() && !dir.exists() && legacy_dir.is_dir() {
+        match std::fs::rename(&legacy_dir, &dir) {
+            Ok(()) => info!("Moved archives of {} to {:?}", account, dir),
+            Err(e) => error!(
+                "Unable to move archives {:?} to {:?}: {:?}",
+                legacy_dir, dir, e
+            ),
+        }
+    }
+
+    dir
+}
+
+// Writes every queued batch of an account to its own archive file, oldest first.
+// Batches which fail stay in queue. Queue is simply emptied if archive was never initialized.
+// Compression and fsync run on the blocking pool, caller's lock is held but executor is not.
+pub async fn store(account: &str, queue: &mut VecDeque<Drained>) {
+    let archive = match ARCHIVE.get() {
+        Some(archive) => archive,
+        None => {
+            queue.clear();
+            return;
+        }
+    };
+
+    if queue.is_empty() {
+        return;
+    }
+
+    let batches = std::mem::take(queue);
+    let owner = account.to_owned();
+    let written =
+        tokio::task::spawn_blocking(move || write_batches(archive, &owner, batches)).await;
+
+    match written {
+        Ok(left) => *queue = left,
+        Err(e) => error!("Archive writer of {} failed: {:?}", account, e),
+    }
+
+    let mut queued: usize = queue.iter().map(Drained::len).sum();
+    while queued > MAX_QUEUED_LOGS {
+        let dropped = queue.pop_front().unwrap_or_default();
+        queued -= dropped.len();
+        error!(
+            "Archive queue of {} is full, dropped {} logs without archiving",
+            account,
+            dropped.len()
+        );
+    }
+}
+
+// Returns batches which couldn't be written
+fn write_batches(
+    archive: &Archive,
+    account: &str,
+    mut batches: VecDeque<Drained>,
+) -> VecDeque<Drained> {
+    while let Some(drained) = batches.front() {
+        match write_archive(archive, account, drained) {
+            Ok(path) => info!("Archived cleared logs of {} to {:?}", account, path),
+            Err(e) => {
+                error!("Unable to archive cleared logs of {}: {:?}", account, e);
+                break;
+            }
+        }
+        batches.pop_front();
+    }
+
+    if let Err(e) = rotate(archive, account) {
+        error!("Unable to rotate archives of {}: {:?}", account, e);
+    }
+
+    batches
+}
+
+// Names have millisecond resolution, a counter is added when two archives land in same one
+fn create_archive_file(dir: &Path, account: &str) -> io::Result<(File, PathBuf)> {
+    let now = time::OffsetDateTime::now_utc();
+    let stem = format!(
+        "{}_{}{:03}",
+        crate::helpers::short_sanitize(account),
+        now.format("%Y%m%dT%H%M%S"),
+        now.millisecond()
+    );
+
+    let mut n = 0;
+    loop {
+        let name = match n {
+            0 => format!("{}{}", stem, ARCHIVE_EXTENSION),
+            n => format!("{}_{}{}", stem, n, ARCHIVE_EXTENSION),
+        };
+        let path = dir.join(name);
+
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((file, path)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn write_archive(archive: &Archive, account: &str, drained: &Drained) -> io::Result<PathBuf> {
+    let dir = account_dir(archive, account);
+    std::fs::create_dir_all(&dir)?;
+
+    let (file, path) = create_archive_file(&dir, account)?;
+    let result = write_logs(file, drained);
+    if result.is_err() {
+        // Half written file would look like a complete archive
+        let _ = std::fs::remove_file(&path);
+    }
+
+    result.map(|_| path)
+}
+
+fn write_logs(file: File, drained: &Drained) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(file, Compression::default());
+
+    let main_logs = drained.main_logs.iter().map(|log| ArchivedLog {
+        keyword_id: None,
+        log,
+    });
+    let keyword_logs = drained.keyword_logs.iter().flat_map(|(id, logs)| {
+        logs.iter().map(move |log| ArchivedLog {
+            keyword_id: Some(*id),
+            log,
+        })
+    });
+
+    for archived in main_logs.chain(keyword_logs) {
+        serde_json::to_writer(&mut encoder, &archived)?;
+        encoder.write_all(b"\n")?;
+    }
+
+    encoder.finish()?.sync_all()
+}
+
+// Deletes oldest archives so only max_files remain.
+// File names start with utc time so sorting by name is sorting by age.
+fn rotate(archive: &Archive, account: &str) -> io::Result<()> {
+    let mut files = list_files(&account_dir(archive, account))?;
+
+    if files.len() <= archive.max_files {
+        return Ok(());
+    }
+
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let remove = files.len() - archive.max_files;
+    for file in files.iter().take(remove) {
+        std::fs::remove_file(account_dir(archive, account).join(&file.name))?;
+    }
+
+    Ok(())
+}
+
+fn list_files(dir: &Path) -> io::Result<Vec<ArchiveFile>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if name.ends_with(ARCHIVE_EXTENSION) {
+            files.push(ArchiveFile {
+                name,
+                size: entry.metadata()?.len(),
+            });
+        }
+    }
+
+    Ok(files)
+}
+
+// Archives of account, newest first
+pub fn list(account: &str) -> io::Result<Vec<ArchiveFile>> {
+    let archive = match ARCHIVE.get() {
+        Some(archive) => archive,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut files = list_files(&account_dir(archive, account))?;
+    files.sort_by(|a, b| b.name.cmp(&a.name));
+
+    Ok(files)
+}
+
+// Returns path of archive only if name is a plain archive file name.
+// This stops `../` style names from reading outside archive directory.
+pub fn path_of(account: &str, name: &str) -> Option<PathBuf> {
+    let archive = ARCHIVE.get()?;
+
+    if !name.ends_with(ARCHIVE_EXTENSION) || name.contains("..") || name.contains(['/', '\\']) {
+        return None;
+    }
+
+    let path = account_dir(archive, account).join(name);
+    if path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
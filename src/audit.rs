@@ -0,0 +1,63 @@
+// Audit trail of destructive operations.
+// One json object per line in `<data_dir>/audit.log`. Never compacted or rotated.
+use crate::models::ClearSummary;
+use crate::timestamp::Timestamp;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::error;
+
+const AUDIT_FILE: &str = "audit.log";
+
+static AUDIT: OnceCell<Mutex<File>> = OnceCell::new();
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AuditEntry {
+    pub time: Timestamp,
+    pub action: String,
+    pub account: String,
+
+    // Name of api key used, if any
+    pub key_name: Option<String>,
+    pub remote_addr: Option<String>,
+
+    pub counters_reset: bool,
+    pub drained: ClearSummary,
+}
+
+pub fn init(data_dir: &Path) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(data_dir.join(AUDIT_FILE))?;
+
+    if AUDIT.set(Mutex::new(file)).is_err() {
+        error!("Audit log was already initialized");
+    }
+
+    Ok(())
+}
+
+// Appends entry and syncs it to disk. Does nothing if audit log was never initialized.
+pub fn record(entry: &AuditEntry) {
+    let audit = match AUDIT.get() {
+        Some(audit) => audit,
+        None => return,
+    };
+
+    let result = serde_json::to_vec(entry)
+        .map_err(io::Error::from)
+        .and_then(|mut line| {
+            line.push(b'\n');
+            let mut file = audit.lock().unwrap();
+            file.write_all(&line)?;
+            file.sync_data()
+        });
+
+    if let Err(e) = result {
+        error!("Unable to write audit entry {:?}: {:?}", entry, e);
+    }
+}
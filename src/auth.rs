@@ -0,0 +1,170 @@
+// API keys.
+// Keys are defined in config.toml as `[[api_keys]]`, each one limited to some accounts and scopes.
+// Clients send key as `Authorization: Bearer <key>`, `X-Api-Key: <key>` or `?api_key=<key>`
+// (browsers can't set headers for EventSource and WebSocket).
+// If no key is configured every request is allowed like before.
+use crate::errors::ApiError;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tracing::{info, warn};
+use warp::filters::path::FullPath;
+use warp::{Filter, Rejection};
+
+static KEYS: OnceCell<Vec<ApiKey>> = OnceCell::new();
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ApiKey {
+    // Only used in logs
+    pub name: Option<String>,
+    pub key: String,
+
+    // Accounts this key can access. `*` means every account.
+    pub accounts: Vec<String>,
+    pub scopes: Vec<Scope>,
+}
+
+impl ApiKey {
+    fn allows(&self, account: &str, scope: Scope) -> bool {
+        self.scopes.contains(&scope) && self.can_access(account)
+    }
+
+    fn can_access(&self, account: &str) -> bool {
+        self.accounts.iter().any(|a| a == "*" || a == account)
+    }
+}
+
+pub fn init(keys: Vec<ApiKey>) {
+    if keys.is_empty() {
+        warn!("No api_keys configured, every request is allowed");
+    }
+
+    let _ = KEYS.set(keys);
+}
+
+fn keys() -> &'static [ApiKey] {
+    KEYS.get().map(|k| k.as_slice()).unwrap_or(&[])
+}
+
+// Compares in constant time so key can't be guessed byte by byte from response times
+fn same_key(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn find_key(presented: &str) -> Option<&'static ApiKey> {
+    keys().iter().find(|k| same_key(&k.key, presented))
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyQuery {
+    api_key: Option<String>,
+}
+
+// Key sent with request, if any
+fn credentials() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and(warp::query::<KeyQuery>())
+        .map(
+            |authorization: Option<String>, api_key: Option<String>, query: KeyQuery| {
+                authorization
+                    .and_then(|a| {
+                        let mut parts = a.splitn(2, ' ');
+                        match (parts.next(), parts.next()) {
+                            (Some(scheme), Some(key)) if scheme.eq_ignore_ascii_case("bearer") => {
+                                Some(key.trim().to_owned())
+                            }
+                            _ => None,
+                        }
+                    })
+                    .or(api_key)
+                    .or(query.api_key)
+            },
+        )
+}
+
+fn check(account: Option<&str>, presented: Option<String>, scope: Scope) -> Result<(), Rejection> {
+    if keys().is_empty() {
+        return Ok(());
+    }
+
+    let key = presented
+        .as_deref()
+        .and_then(find_key)
+        .ok_or(ApiError::Unauthorized)?;
+
+    let allowed = match account {
+        Some(account) => key.allows(account, scope),
+        None => key.scopes.contains(&scope),
+    };
+
+    if allowed {
+        Ok(())
+    } else {
+        info!(
+            "Key {} denied {:?} access to {:?}",
+            key.name.as_deref().unwrap_or("(unnamed)"),
+            scope,
+            account
+        );
+        Err(ApiError::Forbidden.into())
+    }
+}
+
+// Rejects request unless its key has `scope` on the account in first path segment.
+// Add it after path and method filters so unrelated routes aren't answered with 401.
+pub fn authorize(scope: Scope) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path::full()
+        .and(credentials())
+        .and_then(move |path: FullPath, presented: Option<String>| async move {
+            let account = path.as_str().trim_start_matches('/').split('/').next();
+            check(account, presented, scope)
+        })
+        .untuple_one()
+}
+
+// Accounts visible to key of request, for routes not bound to an account
+#[derive(Debug, Clone)]
+pub struct Access(Option<&'static ApiKey>);
+
+impl Access {
+    pub fn can_access(&self, account: &str) -> bool {
+        match self.0 {
+            Some(key) => key.can_access(account),
+            None => true,
+        }
+    }
+}
+
+pub fn access(scope: Scope) -> impl Filter<Extract = (Access,), Error = Rejection> + Clone {
+    credentials().and_then(move |presented: Option<String>| async move {
+        check(None, presented.clone(), scope)?;
+        Ok::<_, Rejection>(Access(presented.as_deref().and_then(find_key)))
+    })
+}
+
+// Who made the request, recorded in audit log
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub key_name: Option<String>,
+    pub remote_addr: Option<String>,
+}
+
+pub fn caller() -> impl Filter<Extract = (Caller,), Error = Rejection> + Clone {
+    credentials().and(warp::addr::remote()).map(
+        |presented: Option<String>, addr: Option<SocketAddr>| Caller {
+            key_name: presented
+                .as_deref()
+                .and_then(find_key)
+                .map(|k| k.name.clone().unwrap_or_else(|| "(unnamed)".to_owned())),
+            remote_addr: addr.map(|a| a.to_string()),
+        },
+    )
+}
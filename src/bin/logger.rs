@@ -40,7 +40,7 @@ fn main() {
 // I think it is better we optimize this for windows only
 fn run_platform() -> Result<(), Box<dyn std::error::Error>> {
     info!("Setting config");
-    let config = cli::get_config(false);
+    let config = cli::get_config(false)?;
 
     info!("Action: {:?}", config.action);
     match config.action {
@@ -57,7 +57,7 @@ fn run_platform() -> Result<(), Box<dyn std::error::Error>> {
         }
         Action::RunService => {
             println!("Make sure you are running using admin rights");
-            let output = Command::new("sc").args(&["start","shopee_service"]).output().expect("Failed to launch");
+            let output = Command::new("sc").args(["start","shopee_service"]).output().expect("Failed to launch");
             println!("Stdout {:?}", output.stdout);
             println!("Stderr {:?}", output.stderr);
         }
@@ -1,4 +1,4 @@
-use shopee_logs_collector::{system_service,utils};
+use shopee_logs_collector::system_service;
 use tracing::info;
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::fmt::format::FmtSpan;
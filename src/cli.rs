@@ -1,13 +1,61 @@
+use crate::alerts::AlertsConfig;
+use crate::api_calls::ApiCallsConfig;
+use crate::auth::ApiKey;
+use crate::liveness::LivenessConfig;
+use crate::log_buffer::LogBuffersConfig;
+use crate::models::OrphanPolicy;
+use crate::retention::RetentionConfig;
+use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 use std::io::{self, BufRead};
 
-static mut CFG: Option<Config> = None;
+static CFG: OnceCell<Config> = OnceCell::new();
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub action: Action,
     pub port: u16,
     pub html_path: String,
+
+    // Directory where account snapshots are stored
+    pub data_dir: String,
+
+    // How often in memory database is written to data_dir
+    pub flush_interval_secs: u64,
+
+    // Whether every journal write is synced to disk. Slower but survives power loss.
+    pub journal_fsync: bool,
+
+    // Cleared logs are archived here. Only archive_max_files newest files are kept per account
+    pub archive_dir: String,
+    pub archive_max_files: usize,
+
+    // Api responses smaller than this are sent uncompressed
+    pub compression_min_size: usize,
+
+    // Largest request body accepted, checked as sent and again after decompression
+    pub max_body_bytes: u64,
+
+    // What happens to logs of keywords which aren't registered yet
+    pub orphan_logs: OrphanPolicy,
+
+    // `[retention]` section. Which logs are kept and for how long
+    pub retention: RetentionConfig,
+
+    // `[[api_keys]]` sections. Without any key api is open to everyone
+    pub api_keys: Vec<ApiKey>,
+
+    // `[alerts]` section. Rules checked on updates and webhooks notified when they fire
+    pub alerts: AlertsConfig,
+
+    // `[api_calls]` section. How long api call rate is kept and quota of accounts
+    pub api_calls: ApiCallsConfig,
+
+    // `[liveness]` section. When a silent bot is considered stale and dead
+    pub liveness: LivenessConfig,
+
+    // `[log_buffers]` section. How many logs are kept per account and per keyword
+    pub log_buffers: LogBuffersConfig,
 }
 
 #[derive(Debug,  Serialize, Deserialize)]
@@ -23,21 +71,29 @@ pub struct ConfigBuilder {
     pub action: Option<String>,
     pub port: Option<u16>,
     pub html_path: Option<String>,
+    pub data_dir: Option<String>,
+    pub flush_interval_secs: Option<u64>,
+    pub journal_fsync: Option<bool>,
+    pub archive_dir: Option<String>,
+    pub archive_max_files: Option<usize>,
+    pub compression_min_size: Option<usize>,
+    pub max_body_bytes: Option<u64>,
+    pub orphan_logs: Option<String>,
+    pub orphan_grace_secs: Option<u64>,
+    pub orphan_max_logs: Option<usize>,
+    pub retention: Option<RetentionConfig>,
+    pub api_keys: Option<Vec<ApiKey>>,
+    pub alerts: Option<AlertsConfig>,
+    pub api_calls: Option<ApiCallsConfig>,
+    pub liveness: Option<LivenessConfig>,
+    pub log_buffers: Option<LogBuffersConfig>,
 }
 
-pub fn get_config(from_service: bool) -> &'static Config {
-   unsafe {
-        if CFG.is_some() {
-            return CFG.as_ref().unwrap();
-        }
-
-            CFG =   Some(create_config(from_service));
-
-        CFG.as_ref().unwrap()
-    }
+pub fn get_config(from_service: bool) -> Result<&'static Config, String> {
+    CFG.get_or_try_init(|| create_config(from_service))
 }
 
-pub fn create_config(from_service: bool) -> Config {
+pub fn create_config(from_service: bool) -> Result<Config, String> {
     let config_toml_path =  std::env::current_exe().unwrap().with_file_name("config.toml");
     println!("The config toml path is {:?}", config_toml_path);
     
@@ -47,7 +103,7 @@ pub fn create_config(from_service: bool) -> Config {
 
     let cfg: ConfigBuilder = toml::from_str(&file).expect("Invalid config.toml file");
     
-    let action  = if from_service == false {
+    let action  = if !from_service {
         println!("Please specify the action. ");
         println!("
             1. Register the service
@@ -72,9 +128,55 @@ pub fn create_config(from_service: bool) -> Config {
         Action::RunDirect
     };
 
-    Config {
+    let data_dir = cfg.data_dir.unwrap_or_else(|| {
+        std::env::current_exe()
+            .unwrap()
+            .with_file_name("data")
+            .to_string_lossy()
+            .to_string()
+    });
+
+    let archive_dir = cfg.archive_dir.unwrap_or_else(|| {
+        std::path::Path::new(&data_dir)
+            .join("archive")
+            .to_string_lossy()
+            .to_string()
+    });
+
+    let orphan_logs = match cfg.orphan_logs.as_deref().unwrap_or("drop") {
+        "drop" => OrphanPolicy::Drop,
+        "create" => OrphanPolicy::Create,
+        "buffer" => OrphanPolicy::Buffer {
+            grace_secs: cfg.orphan_grace_secs.unwrap_or(300),
+            max_logs: cfg
+                .orphan_max_logs
+                .unwrap_or_else(crate::models::default_orphan_max_logs),
+        },
+        other => {
+            return Err(format!(
+                "Invalid orphan_logs {} in config.toml, use drop, create or buffer",
+                other
+            ))
+        }
+    };
+
+    Ok(Config {
         action,
         port: cfg.port.unwrap_or(1729),
         html_path: cfg.html_path.expect("Please specify html path"),
-    }
+        data_dir,
+        flush_interval_secs: cfg.flush_interval_secs.unwrap_or(60),
+        journal_fsync: cfg.journal_fsync.unwrap_or(true),
+        archive_dir,
+        archive_max_files: cfg.archive_max_files.unwrap_or(200),
+        compression_min_size: cfg.compression_min_size.unwrap_or(1024),
+        max_body_bytes: cfg.max_body_bytes.unwrap_or(16 * 1024 * 1024),
+        orphan_logs,
+        retention: cfg.retention.unwrap_or_default(),
+        api_keys: cfg.api_keys.unwrap_or_default(),
+        alerts: cfg.alerts.unwrap_or_default(),
+        api_calls: cfg.api_calls.unwrap_or_default(),
+        liveness: cfg.liveness.unwrap_or_default(),
+        log_buffers: cfg.log_buffers.unwrap_or_default(),
+    })
 }
@@ -0,0 +1,389 @@
+// Content-Encoding support for api routes.
+// Request bodies may be gzip, deflate or zstd compressed. Responses are compressed with the best
+// encoding client accepts if they are at least `compression_min_size` bytes.
+// Event streams, websocket upgrades and already compressed files are left alone.
+//
+// Bodies are limited to `max_body_bytes` both as sent and once decoded, so a small compressed
+// body can't expand into gigabytes.
+use crate::errors::ApiError;
+use bytes::{Buf, Bytes};
+use flate2::read::{DeflateDecoder, GzDecoder};
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+use futures::{Stream, StreamExt};
+use once_cell::sync::OnceCell;
+use serde::de::DeserializeOwned;
+use std::convert::Infallible;
+use std::io::{Read, Write};
+use warp::http::header::{
+    HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY,
+};
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+const DEFAULT_MIN_SIZE: usize = 1024;
+const DEFAULT_MAX_BODY: u64 = 16 * 1024 * 1024;
+
+static MIN_SIZE: OnceCell<usize> = OnceCell::new();
+static MAX_BODY: OnceCell<u64> = OnceCell::new();
+
+pub fn init(min_size: usize, max_body: u64) {
+    let _ = MIN_SIZE.set(min_size);
+    let _ = MAX_BODY.set(max_body);
+}
+
+fn min_size() -> usize {
+    MIN_SIZE.get().copied().unwrap_or(DEFAULT_MIN_SIZE)
+}
+
+fn max_body() -> u64 {
+    MAX_BODY.get().copied().unwrap_or(DEFAULT_MAX_BODY)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Zstd,
+    Gzip,
+    Deflate,
+    Identity,
+}
+
+impl Encoding {
+    fn from_token(token: &str) -> Option<Encoding> {
+        match token.trim().to_lowercase().as_str() {
+            "zstd" => Some(Encoding::Zstd),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            "identity" => Some(Encoding::Identity),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Identity => "identity",
+        }
+    }
+}
+
+pub fn decode(encoding: Option<&str>, body: &[u8], limit: u64) -> Result<Vec<u8>, ApiError> {
+    let encoding = match encoding {
+        None => return Ok(body.to_vec()),
+        Some(e) => Encoding::from_token(e)
+            .ok_or_else(|| ApiError::BadRequest(format!("Unsupported content-encoding {}", e)))?,
+    };
+
+    // One byte over the limit is enough to tell body is too large
+    let mut decoded = Vec::new();
+    let result = match encoding {
+        Encoding::Identity => return Ok(body.to_vec()),
+        Encoding::Gzip => GzDecoder::new(body)
+            .take(limit + 1)
+            .read_to_end(&mut decoded),
+        Encoding::Deflate => DeflateDecoder::new(body)
+            .take(limit + 1)
+            .read_to_end(&mut decoded),
+        Encoding::Zstd => zstd::stream::read::Decoder::new(body)
+            .and_then(|decoder| decoder.take(limit + 1).read_to_end(&mut decoded)),
+    };
+
+    result.map_err(|e| {
+        ApiError::BadRequest(format!(
+            "Unable to decode {} body: {}",
+            encoding.as_str(),
+            e
+        ))
+    })?;
+
+    if decoded.len() as u64 > limit {
+        return Err(ApiError::PayloadTooLarge(format!(
+            "Decoded body is over {} bytes",
+            limit
+        )));
+    }
+
+    Ok(decoded)
+}
+
+fn encode(encoding: Encoding, body: &[u8]) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Identity => Ok(body.to_vec()),
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Zstd => zstd::stream::encode_all(body, 0),
+    }
+}
+
+// Picks best encoding from Accept-Encoding honoring q values. We prefer zstd, then gzip, then deflate.
+pub fn negotiate(accept_encoding: &str) -> Encoding {
+    let mut best: Option<(Encoding, f32)> = None;
+
+    for part in accept_encoding.split(',') {
+        let mut params = part.split(';');
+        let token = params.next().unwrap_or("").trim();
+
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q=")?.parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+
+        if q <= 0.0 {
+            continue;
+        }
+
+        let candidates: Vec<Encoding> = if token == "*" {
+            vec![Encoding::Zstd, Encoding::Gzip, Encoding::Deflate]
+        } else {
+            Encoding::from_token(token).into_iter().collect()
+        };
+
+        for encoding in candidates {
+            let better = match best {
+                None => true,
+                Some((current, current_q)) => {
+                    q > current_q || (q == current_q && rank(encoding) < rank(current))
+                }
+            };
+
+            if better {
+                best = Some((encoding, q));
+            }
+        }
+    }
+
+    best.map(|(e, _)| e).unwrap_or(Encoding::Identity)
+}
+
+fn rank(encoding: Encoding) -> u8 {
+    match encoding {
+        Encoding::Zstd => 0,
+        Encoding::Gzip => 1,
+        Encoding::Deflate => 2,
+        Encoding::Identity => 3,
+    }
+}
+
+// Reads whole body, giving up as soon as it is over limit. Chunked and empty bodies have no
+// Content-Length so the limit can't be checked from headers.
+async fn read_body(
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
+    limit: u64,
+) -> Result<Vec<u8>, ApiError> {
+    futures::pin_mut!(body);
+
+    let mut read = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk
+            .map_err(|e| ApiError::BadRequest(format!("Unable to read body: {}", e)))?
+            .to_bytes();
+
+        if (read.len() + chunk.len()) as u64 > limit {
+            return Err(ApiError::PayloadTooLarge(format!(
+                "Body is over {} bytes",
+                limit
+            )));
+        }
+        read.extend_from_slice(&chunk);
+    }
+
+    Ok(read)
+}
+
+// Raw request body with Content-Encoding removed
+pub fn decoded_body() -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    let limit = max_body();
+
+    warp::header::optional::<String>("content-encoding")
+        .and(warp::body::stream())
+        .and_then(move |encoding: Option<String>, body| async move {
+            let body = read_body(body, limit).await?;
+            decode(encoding.as_deref(), &body, limit)
+                .map(Bytes::from)
+                .map_err(Rejection::from)
+        })
+}
+
+// Same as `warp::body::json` but understands compressed bodies
+pub fn json_body<T: DeserializeOwned + Send>(
+) -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    decoded_body().and_then(|body: Bytes| async move {
+        serde_json::from_slice::<T>(&body).map_err(|e| Rejection::from(ApiError::from_json(e)))
+    })
+}
+
+fn should_compress(response: &warp::reply::Response) -> bool {
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS
+        || response.headers().contains_key(CONTENT_ENCODING)
+    {
+        return false;
+    }
+
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .unwrap_or("");
+
+    // Streams never end so they can't be buffered. Archives are already compressed.
+    !(content_type.starts_with("text/event-stream")
+        || content_type.contains("gzip")
+        || content_type.contains("zstd")
+        || content_type.contains("zip"))
+}
+
+pub async fn compress_reply(
+    accept_encoding: Option<String>,
+    reply: impl Reply,
+) -> Result<warp::reply::Response, Infallible> {
+    let response = reply.into_response();
+
+    let encoding = match accept_encoding {
+        Some(accept) => negotiate(&accept),
+        None => Encoding::Identity,
+    };
+
+    if encoding == Encoding::Identity || !should_compress(&response) {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(_) => {
+            return Ok(warp::reply::with_status(warp::reply(), StatusCode::INTERNAL_SERVER_ERROR)
+                .into_response())
+        }
+    };
+
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static(ACCEPT_ENCODING.as_str()));
+
+    if body.len() < min_size() {
+        return Ok(warp::reply::Response::from_parts(parts, body.into()));
+    }
+
+    match encode(encoding, &body) {
+        Ok(compressed) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+            Ok(warp::reply::Response::from_parts(parts, compressed.into()))
+        }
+        Err(_) => Ok(warp::reply::Response::from_parts(parts, body.into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        encode(Encoding::Gzip, body).unwrap()
+    }
+
+    #[test]
+    fn negotiates_by_q_then_preference() {
+        assert_eq!(negotiate("gzip, deflate, zstd"), Encoding::Zstd);
+        assert_eq!(negotiate("gzip;q=1.0, zstd;q=0.5"), Encoding::Gzip);
+        assert_eq!(negotiate("deflate, gzip"), Encoding::Gzip);
+        assert_eq!(negotiate("*"), Encoding::Zstd);
+        assert_eq!(negotiate("*;q=0.5, deflate"), Encoding::Deflate);
+        assert_eq!(negotiate("zstd;q=0, gzip;q=0"), Encoding::Identity);
+        assert_eq!(negotiate("br, compress"), Encoding::Identity);
+        assert_eq!(negotiate(""), Encoding::Identity);
+    }
+
+    #[test]
+    fn decodes_every_encoding() {
+        let body = b"{\"message\": \"hello\"}".repeat(10);
+
+        for encoding in [
+            Encoding::Zstd,
+            Encoding::Gzip,
+            Encoding::Deflate,
+            Encoding::Identity,
+        ] {
+            let encoded = encode(encoding, &body).unwrap();
+            let decoded = decode(Some(encoding.as_str()), &encoded, 1024).unwrap();
+            assert_eq!(decoded, body, "{:?}", encoding);
+        }
+
+        assert_eq!(decode(None, &body, 1024).unwrap(), body);
+        assert!(matches!(
+            decode(Some("br"), &body, 1024),
+            Err(ApiError::BadRequest(_))
+        ));
+        assert!(matches!(
+            decode(Some("gzip"), b"not gzip", 1024),
+            Err(ApiError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn caps_decoded_size() {
+        let bomb = gzip(&vec![0; 1024 * 1024]);
+        assert!(bomb.len() < 10 * 1024);
+
+        assert!(matches!(
+            decode(Some("gzip"), &bomb, 1024),
+            Err(ApiError::PayloadTooLarge(_))
+        ));
+        assert_eq!(
+            decode(Some("gzip"), &gzip(&[1; 1024]), 1024).unwrap().len(),
+            1024
+        );
+    }
+
+    #[tokio::test]
+    async fn caps_streamed_size() {
+        let chunks = |sizes: &[usize]| {
+            let chunks: Vec<Result<Bytes, warp::Error>> = sizes
+                .iter()
+                .map(|&n| Ok(Bytes::from(vec![b'x'; n])))
+                .collect();
+            futures::stream::iter(chunks)
+        };
+
+        assert_eq!(
+            read_body(chunks(&[400, 600]), 1000).await.unwrap().len(),
+            1000
+        );
+        assert!(matches!(
+            read_body(chunks(&[400, 601]), 1000).await,
+            Err(ApiError::PayloadTooLarge(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_bodies_without_content_length() {
+        let empty = warp::test::request()
+            .method("POST")
+            .filter(&decoded_body())
+            .await
+            .unwrap();
+        assert!(empty.is_empty());
+
+        let body = warp::test::request()
+            .method("POST")
+            .header("content-encoding", "gzip")
+            .body(gzip(b"[1, 2, 3]"))
+            .filter(&json_body::<Vec<u8>>())
+            .await
+            .unwrap();
+        assert_eq!(body, vec![1, 2, 3]);
+    }
+}
@@ -1,153 +1,721 @@
-use crate::models::clear_db;
-use crate::models::{Db, KeywordStatistics, Log, Statistics, UpdateKeywordStat, UpdateStat};
+use crate::api_calls::ApiCallsQuery;
+use crate::archive;
+use crate::audit::{self, AuditEntry};
+use crate::errors::ApiError;
+use crate::auth::{Access, Caller};
+use crate::events::{self, StatsMessage, TailFilter, TailQuery};
+use crate::history::HistoryQuery;
+use crate::issues::IssuesQuery;
+use crate::liveness::{self, Heartbeat};
+use crate::metrics;
+use crate::models::{
+    commit, orphan_policy, preview_clear, BatchLog, ClearQuery, Db, Event, Log, OrphanPolicy,
+    UpdateKeywordStat, UpdateStat,
+};
+use crate::query::{LogFilter, LogQuery};
+use crate::runs::{EndRun, RunsQuery, StartRun};
+use crate::severity::SeverityQuery;
+use crate::timestamp::{RenderQuery, Timestamp};
+use futures::future::ready;
+use futures::{SinkExt, StreamExt};
 use serde_json::json;
-use serde_json::Value::Null;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
 use std::convert::Infallible;
+use tokio::sync::broadcast::RecvError;
 use warp::reply::json;
+use warp::ws::{Message, WebSocket};
+use warp::Rejection;
 
-pub async fn list_accounts(db: Db) -> Result<impl warp::Reply, Infallible> {
-    let lock = db.read().await;
+pub async fn list_accounts(access: Access, db: Db) -> Result<impl warp::Reply, Infallible> {
+    let now = Timestamp::now();
 
-    let keys = lock.keys().into_iter().collect::<Vec<&String>>();
-    let json = serde_json::json!({ "accounts": keys });
+    let mut keys = Vec::new();
+    let mut liveness: HashMap<String, liveness::Status> = HashMap::new();
+    for (account, slot) in db.all() {
+        if !access.can_access(&account) {
+            continue;
+        }
+
+        let statistics = metrics::read_account(&slot).await;
+        liveness.insert(account.clone(), liveness::main_status(&statistics.main_stats, now));
+        keys.push(account);
+    }
+
+    let json = serde_json::json!({ "accounts": keys, "liveness": liveness });
     Ok(warp::reply::json(&json))
 }
 
+// Prometheus scrape of accounts visible to key plus server internals
+pub async fn metrics(access: Access, db: Db) -> Result<impl warp::Reply, Infallible> {
+    let mut slots: Vec<_> = db
+        .all()
+        .into_iter()
+        .filter(|(account, _)| access.can_access(account))
+        .collect();
+
+    // Sorted so scrapes are stable and easy to diff
+    slots.sort_by(|a, b| a.0.cmp(&b.0));
+
+    // Only one account is locked at a time
+    let mut scrape = metrics::AccountMetrics::new();
+    for (_, slot) in slots {
+        let statistics = metrics::read_account(&slot).await;
+        scrape.add(&statistics);
+    }
+    let body = scrape.render();
+
+    Ok(warp::reply::with_header(
+        body,
+        "content-type",
+        "text/plain; version=0.0.4; charset=utf-8",
+    ))
+}
+
 pub async fn get_keyword_logs(
     account: String,
     keyword_id: u64,
+    query: SeverityQuery,
+    render: RenderQuery,
     db: Db,
-) -> Result<impl warp::Reply, Infallible> {
-    let lock = db.read().await;
+) -> Result<impl warp::Reply, Rejection> {
+    render.validate().map_err(ApiError::BadRequest)?;
+    query.validate().map_err(ApiError::BadRequest)?;
+
+    let slot = db
+        .get(&account)
+        .ok_or_else(|| ApiError::AccountNotFound(account.clone()))?;
+    let statistics = metrics::read_account(&slot).await;
+
+    let ks = statistics
+        .keyword_stats
+        .get(&keyword_id)
+        .ok_or_else(|| ApiError::KeywordNotFound(account.clone(), keyword_id))?;
+
+    let logs: Vec<&Log> = ks
+        .keyword_logs
+        .iter()
+        .filter(|log| query.matches(&log.r#type))
+        .collect();
+
+    Ok(render.render(|| json(&logs)))
+}
 
-    if let Some(statistics) = lock.get(&account) {
-        let keyword_stats = &statistics.keyword_stats;
+pub async fn get_keyword_history(
+    account: String,
+    keyword_id: u64,
+    query: HistoryQuery,
+    render: RenderQuery,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    render.validate().map_err(ApiError::BadRequest)?;
 
-        if let Some(ks) = keyword_stats.get(&keyword_id) {
-            return Ok(json(&ks.keyword_logs));
-        }
-    }
+    let slot = db
+        .get(&account)
+        .ok_or_else(|| ApiError::AccountNotFound(account.clone()))?;
+    let statistics = metrics::read_account(&slot).await;
 
-    Ok(json(&Null))
+    let ks = statistics
+        .keyword_stats
+        .get(&keyword_id)
+        .ok_or_else(|| ApiError::KeywordNotFound(account.clone(), keyword_id))?;
+
+    let page = query
+        .run(keyword_id, &ks.history)
+        .map_err(ApiError::BadRequest)?;
+
+    Ok(render.render(|| json(&page)))
 }
 
-pub async fn get_main_stats(account: String, db: Db) -> Result<impl warp::Reply, Infallible> {
-    let lock = db.read().await;
-    if let Some(statistics) = lock.get(&account) {
-        let main_stats = &statistics.main_stats;
-        let keyword_stats: Vec<_> = statistics
-            .keyword_stats
-            .values()
-            .map(|v| &v.stats)
-            .collect();
+pub async fn get_api_calls(
+    account: String,
+    query: ApiCallsQuery,
+    render: RenderQuery,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    render.validate().map_err(ApiError::BadRequest)?;
 
-        let ret = &json!({
-            "main_stats": main_stats,
-            "keyword_stats": keyword_stats
-        });
+    let slot = db
+        .get(&account)
+        .ok_or_else(|| ApiError::AccountNotFound(account.clone()))?;
+    let statistics = metrics::read_account(&slot).await;
 
-        return Ok(json(ret));
-    }
+    let page = query
+        .run(&account, &statistics.api_calls, Timestamp::now())
+        .map_err(ApiError::BadRequest)?;
 
-    Ok(json(&Null))
+    Ok(render.render(|| json(&page)))
 }
 
-pub async fn clear_log(account: String, db: Db) -> Result<impl warp::Reply, Infallible> {
-    let mut lock = db.write().await;
+pub async fn list_issues(
+    account: String,
+    query: IssuesQuery,
+    render: RenderQuery,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    render.validate().map_err(ApiError::BadRequest)?;
 
-    if let Some(statistics) = lock.get_mut(&account) {
-        clear_db(statistics, 100).await
-    }
+    let slot = db
+        .get(&account)
+        .ok_or_else(|| ApiError::AccountNotFound(account.clone()))?;
+    let statistics = metrics::read_account(&slot).await;
 
-    // For now just send success message
-    Ok(json(&json!({"type": "success",})))
+    let page = query.run(&statistics.issues).map_err(ApiError::BadRequest)?;
+    Ok(render.render(|| json(&page)))
 }
 
-pub async fn clear_log_full(account: String, db: Db) -> Result<impl warp::Reply, Infallible> {
-    let mut lock = db.write().await;
+pub async fn get_issue(
+    account: String,
+    fingerprint: String,
+    render: RenderQuery,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    render.validate().map_err(ApiError::BadRequest)?;
 
-    if let Some(statistics) = lock.get_mut(&account) {
-        clear_db(statistics, 0).await
-    }
+    let slot = db
+        .get(&account)
+        .ok_or_else(|| ApiError::AccountNotFound(account.clone()))?;
+    let statistics = metrics::read_account(&slot).await;
 
-    // For now just send success message
-    Ok(json(&json!({"type": "success",})))
+    let issue = statistics
+        .issues
+        .get(&fingerprint)
+        .ok_or_else(|| ApiError::IssueNotFound(account.clone(), fingerprint.clone()))?;
+
+    Ok(render.render(|| json(issue)))
 }
 
-pub async fn update_stats(
+pub async fn query_logs(
     account: String,
-    req: UpdateStat,
+    query: LogQuery,
+    render: RenderQuery,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    render.validate().map_err(ApiError::BadRequest)?;
+    let filter = LogFilter::parse(&query).map_err(ApiError::BadRequest)?;
+
+    let slot = db
+        .get(&account)
+        .ok_or_else(|| ApiError::AccountNotFound(account.clone()))?;
+    let statistics = metrics::read_account(&slot).await;
+
+    let page = filter.run(&statistics);
+    Ok(render.render(|| json(&page)))
+}
+
+pub async fn tail_logs(
+    account: String,
+    query: TailQuery,
+    render: RenderQuery,
+    last_event_id: Option<u64>,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    render.validate().map_err(ApiError::BadRequest)?;
+
+    let filter = TailFilter::new(account.clone(), &query).map_err(ApiError::BadRequest)?;
+    let since = last_event_id.or(query.since);
+
+    // Subscribe while holding the lock. Logs are only added under write lock of the account so
+    // nothing can slip in between backlog and live events.
+    let (backlog, receiver) = match (since, db.get(&account)) {
+        (Some(since), Some(slot)) => {
+            let statistics = metrics::read_account(&slot).await;
+            (filter.backlog(&statistics, since), events::subscribe_logs())
+        }
+        _ => (Vec::new(), events::subscribe_logs()),
+    };
+
+    let last_seq = backlog.last().map(|e| e.log.seq).unwrap_or(0);
+
+    // Lagging receiver ends the stream so browser reconnects with last event id
+    let live = receiver
+        .take_while(|event| ready(event.is_ok()))
+        .filter_map(move |event| {
+            ready(
+                event
+                    .ok()
+                    .filter(|event| event.log.seq > last_seq && filter.matches(event)),
+            )
+        });
+
+    let stream = futures::stream::iter(backlog).chain(live).map(move |event| {
+        let data = render.render(|| serde_json::to_string(&event).unwrap_or_default());
+
+        Ok::<_, Infallible>((
+            warp::sse::id(event.log.seq.to_string()),
+            warp::sse::event("log"),
+            warp::sse::data(data),
+        ))
+    });
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
+}
+
+pub async fn stats_feed(
+    account: String,
+    ws: warp::ws::Ws,
     db: Db,
 ) -> Result<impl warp::Reply, Infallible> {
-    let mut lock = db.write().await;
+    Ok(ws.on_upgrade(move |socket| stats_socket(socket, account, db)))
+}
+
+async fn stats_snapshot(account: &str, db: &Db) -> Option<StatsMessage> {
+    let slot = db.get(account)?;
+    let statistics = metrics::read_account(&slot).await;
+    Some(events::snapshot(&statistics))
+}
 
-    if lock.get_mut(&account).is_none() {
-        lock.insert(account.clone(), Statistics::new(account.clone()));
+async fn send_stats(tx: &mut futures::stream::SplitSink<WebSocket, Message>, message: &StatsMessage) -> bool {
+    match serde_json::to_string(message) {
+        Ok(text) => tx.send(Message::text(text)).await.is_ok(),
+        Err(_) => true,
     }
+}
 
-    if let Some(statistics) = lock.get_mut(&account) {
-        let mut main_stats = &mut statistics.main_stats;
-        main_stats.last_updated_at = crate::helpers::current_time_string();
+async fn stats_socket(socket: WebSocket, account: String, db: Db) {
+    let (mut tx, mut rx) = socket.split();
 
-        if req.error_counts.is_some() {
-            main_stats.error_counts += req.error_counts.unwrap();
+    // Subscribe while holding the lock so no change is missed between snapshot and feed
+    let (snapshot, mut changes) = match db.get(&account) {
+        Some(slot) => {
+            let statistics = metrics::read_account(&slot).await;
+            (Some(events::snapshot(&statistics)), events::subscribe_stats())
         }
+        None => (None, events::subscribe_stats()),
+    };
 
-        if req.running.is_some() {
-            main_stats.running = req.running.unwrap();
+    if let Some(snapshot) = snapshot {
+        if !send_stats(&mut tx, &snapshot).await {
+            return;
         }
+    }
+
+    loop {
+        tokio::select! {
+            incoming = rx.next() => {
+                match incoming {
+                    Some(Ok(message)) if !message.is_close() => continue,
+                    _ => break,
+                }
+            }
+            change = changes.recv() => {
+                let message = match change {
+                    Ok(message) if message.account() == account => message,
+                    Ok(_) => continue,
+                    // We missed some changes, start over with fresh snapshot
+                    Err(RecvError::Lagged(_)) => {
+                        changes = events::subscribe_stats();
+                        match stats_snapshot(&account, &db).await {
+                            Some(snapshot) => snapshot,
+                            None => continue,
+                        }
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                if !send_stats(&mut tx, &message).await {
+                    break;
+                }
+            }
+        }
+    }
+}
 
-        if req.no_of_api_call_diff.is_some() {
-            main_stats.no_api_calls += req.no_of_api_call_diff.unwrap();
+pub async fn get_main_stats(
+    account: String,
+    query: SeverityQuery,
+    render: RenderQuery,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    render.validate().map_err(ApiError::BadRequest)?;
+    query.validate().map_err(ApiError::BadRequest)?;
+
+    let slot = db
+        .get(&account)
+        .ok_or_else(|| ApiError::AccountNotFound(account.clone()))?;
+    let statistics = metrics::read_account(&slot).await;
+
+    let main_stats = &statistics.main_stats;
+    let keyword_stats: Vec<_> = statistics
+        .keyword_stats
+        .values()
+        .map(|v| &v.stats)
+        .collect();
+
+    let now = Timestamp::now();
+    let keyword_liveness: HashMap<u64, liveness::Status> = keyword_stats
+        .iter()
+        .map(|ks| (ks.id, liveness::keyword_status(ks, now)))
+        .collect();
+
+    // json! serializes right away so timestamps are rendered inside this closure
+    let ret = render.render(|| {
+        let mut ret = json!({
+            "main_stats": main_stats,
+            "keyword_stats": keyword_stats,
+            "liveness": {
+                "main": liveness::main_status(main_stats, now),
+                "keywords": keyword_liveness,
+            }
+        });
+
+        if query.min_severity.is_some() {
+            let logs: Vec<&Log> = main_stats
+                .logs
+                .iter()
+                .filter(|log| query.matches(&log.r#type))
+                .collect();
+            ret["main_stats"]["logs"] = json!(logs);
         }
+
+        ret
+    });
+
+    Ok(json(&ret))
+}
+
+pub async fn clear_log(
+    account: String,
+    query: ClearQuery,
+    caller: Caller,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    clear(account, 100, query, caller, db).await
+}
+
+pub async fn clear_log_full(
+    account: String,
+    query: ClearQuery,
+    caller: Caller,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    clear(account, 0, query, caller, db).await
+}
+
+// Keeps newest `count` logs of every list. Clearing down to 0 also resets counters.
+async fn clear(
+    account: String,
+    count: usize,
+    query: ClearQuery,
+    caller: Caller,
+    db: Db,
+) -> Result<warp::reply::Json, Rejection> {
+    let counters_reset = count == 0;
+
+    let slot = db
+        .get(&account)
+        .ok_or_else(|| ApiError::AccountNotFound(account.clone()))?;
+
+    if query.dry_run {
+        let statistics = metrics::read_account(&slot).await;
+        let drained = preview_clear(&statistics, count);
+
+        return Ok(json(&json!({
+            "type": "success",
+            "dry_run": true,
+            "counters_reset": counters_reset,
+            "drained": drained,
+        })));
     }
 
+    let mut statistics = metrics::write_account(&slot).await;
+    let drained = commit(
+        &mut statistics,
+        Event::Clear {
+            account: account.clone(),
+            count,
+        },
+    )
+    .await
+    .map_err(ApiError::from_journal)?
+    .unwrap_or_default();
+
+    audit::record(&AuditEntry {
+        time: Timestamp::now(),
+        action: if counters_reset { "clear_log_full" } else { "clear_log" }.to_owned(),
+        account,
+        key_name: caller.key_name,
+        remote_addr: caller.remote_addr,
+        counters_reset,
+        drained: drained.clone(),
+    });
+
+    Ok(json(&json!({
+        "type": "success",
+        "dry_run": false,
+        "counters_reset": counters_reset,
+        "drained": drained,
+    })))
+}
+
+pub async fn update_stats(
+    account: String,
+    req: UpdateStat,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    let slot = db.get_or_create(&account);
+    let mut statistics = metrics::write_account(&slot).await;
+
+    commit(&mut statistics, Event::UpdateStats { account, input: req })
+        .await
+        .map_err(ApiError::from_journal)?;
+
+    Ok(json(&json!({
+        "type": "success",
+    })))
+}
+
+// Body is optional, an empty post is a plain heartbeat
+pub async fn heartbeat(
+    account: String,
+    body: bytes::Bytes,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    let input: Heartbeat = if body.is_empty() {
+        Heartbeat::default()
+    } else {
+        serde_json::from_slice(&body).map_err(ApiError::from_json)?
+    };
+
+    let slot = db.get_or_create(&account);
+    let mut statistics = metrics::write_account(&slot).await;
+    commit(&mut statistics, Event::Heartbeat { account, input })
+        .await
+        .map_err(ApiError::from_journal)?;
+
+    Ok(json(&json!({
+        "type": "success",
+    })))
+}
+
+pub async fn start_run(
+    account: String,
+    req: StartRun,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    let slot = db.get_or_create(&account);
+    let mut statistics = metrics::write_account(&slot).await;
+
+    commit(
+        &mut statistics,
+        Event::StartRun {
+            account,
+            input: req,
+        },
+    )
+    .await
+    .map_err(ApiError::from_journal)?;
+
+    let run = statistics.runs.current.as_ref();
     Ok(json(&json!({
         "type": "success",
+        "run": run,
     })))
 }
 
+pub async fn end_run(
+    account: String,
+    req: EndRun,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    let slot = db
+        .get(&account)
+        .ok_or_else(|| ApiError::AccountNotFound(account.clone()))?;
+    let mut statistics = metrics::write_account(&slot).await;
+
+    if statistics.runs.current.is_none() {
+        return Err(ApiError::Conflict(format!("No run of {} is open", account)).into());
+    }
+
+    commit(
+        &mut statistics,
+        Event::EndRun {
+            account,
+            input: req,
+        },
+    )
+    .await
+    .map_err(ApiError::from_journal)?;
+
+    let run = statistics.runs.history.back();
+    Ok(json(&json!({
+        "type": "success",
+        "run": run,
+    })))
+}
+
+pub async fn list_runs(
+    account: String,
+    query: RunsQuery,
+    render: RenderQuery,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    render.validate().map_err(ApiError::BadRequest)?;
+
+    let slot = db
+        .get(&account)
+        .ok_or_else(|| ApiError::AccountNotFound(account.clone()))?;
+    let statistics = metrics::read_account(&slot).await;
+
+    let page = query.run(&statistics.runs);
+    Ok(render.render(|| json(&page)))
+}
+
 pub async fn add_logs_to_stats(
     account: String,
     req: Log,
     db: Db,
-) -> Result<impl warp::Reply, Infallible> {
-    let mut lock = db.write().await;
+) -> Result<impl warp::Reply, Rejection> {
+    let slot = db.get_or_create(&account);
+    let mut statistics = metrics::write_account(&slot).await;
 
-    if lock.get_mut(&account).is_none() {
-        lock.insert(account.clone(), Statistics::new(account.clone()));
+    commit(&mut statistics, Event::AddLog { account, input: req })
+        .await
+        .map_err(ApiError::from_journal)?;
+
+    Ok(json(&json!({"type": "success",})))
+}
+
+// Body is either json array or newline delimited json. Each item is parsed on its own
+// so one bad line doesn't reject whole batch.
+fn parse_batch(body: &[u8]) -> Result<Vec<Result<BatchLog, String>>, String> {
+    let parse = |value: Value| serde_json::from_value::<BatchLog>(value).map_err(|e| e.to_string());
+
+    let is_array = body
+        .iter()
+        .find(|c| !c.is_ascii_whitespace())
+        .map(|c| *c == b'[')
+        .unwrap_or(false);
+
+    if is_array {
+        let values: Vec<Value> =
+            serde_json::from_slice(body).map_err(|e| format!("Invalid json array: {}", e))?;
+        return Ok(values.into_iter().map(parse).collect());
     }
 
-    if let Some(stats) = lock.get_mut(&account) {
-        let main_stats = &mut stats.main_stats;
-        main_stats.last_updated_at = crate::helpers::current_time_string();
+    let body = std::str::from_utf8(body).map_err(|e| format!("Invalid utf8: {}", e))?;
+
+    Ok(body
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            serde_json::from_str::<Value>(line)
+                .map_err(|e| e.to_string())
+                .and_then(parse)
+        })
+        .collect())
+}
 
-        if req.r#type == "error" {
-            main_stats.error_counts += 1
-        }
+pub async fn add_logs_batch(
+    account: String,
+    body: bytes::Bytes,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    let items = parse_batch(&body).map_err(ApiError::BadRequest)?;
+
+    // Main logs create the account. Logs of unknown keywords are handled as orphan policy says.
+    let orphans = orphan_policy();
+    let creates_account = items
+        .iter()
+        .flatten()
+        .any(|item| item.keyword_id.is_none() || orphans != OrphanPolicy::Drop);
+
+    let slot = match db.get(&account) {
+        Some(slot) => Some(slot),
+        None if creates_account => Some(db.get_or_create(&account)),
+        None => None,
+    };
+    let mut lock = match &slot {
+        Some(slot) => Some(metrics::write_account(slot).await),
+        None => None,
+    };
+
+    let mut created = HashSet::new();
+    let mut results = Vec::with_capacity(items.len());
+    let mut accepted = Vec::with_capacity(items.len());
+    let (mut dropped, mut invalid) = (0, 0);
+
+    for (index, item) in items.into_iter().enumerate() {
+        let item = match item {
+            Ok(item) => item,
+            Err(message) => {
+                invalid += 1;
+                results.push(json!({"index": index, "status": "invalid", "message": message}));
+                continue;
+            }
+        };
+
+        let known = match item.keyword_id {
+            None => true,
+            Some(id) => lock
+                .as_ref()
+                .map(|s| s.keyword_stats.contains_key(&id))
+                .unwrap_or(false),
+        };
+
+        let status = match (known, orphans) {
+            (true, _) => "accepted",
+            (false, OrphanPolicy::Drop) => {
+                dropped += 1;
+                results.push(json!({"index": index, "status": "dropped", "message": "unknown keyword"}));
+                continue;
+            }
+            (false, OrphanPolicy::Create) => {
+                // Only first log of a keyword creates it
+                if created.insert(item.keyword_id) {
+                    "created"
+                } else {
+                    "accepted"
+                }
+            }
+            (false, OrphanPolicy::Buffer { .. }) => "buffered",
+        };
+
+        results.push(json!({"index": index, "status": status}));
+        accepted.push(item);
+    }
 
-        main_stats.logs.push(req);
+    // Without an account nothing was accepted, or counted as dropped
+    let no_of_accepted = accepted.len();
+    match lock.as_mut() {
+        Some(statistics) if !accepted.is_empty() || dropped > 0 => {
+            commit(
+                statistics,
+                Event::AddLogBatch {
+                    account,
+                    input: accepted,
+                    orphans,
+                    dropped,
+                },
+            )
+            .await
+            .map_err(ApiError::from_journal)?;
+        }
+        _ => {}
     }
 
-    Ok(json(&json!({"type": "success",})))
+    Ok(json(&json!({
+        "type": "success",
+        "accepted": no_of_accepted,
+        "dropped": dropped,
+        "invalid": invalid,
+        "results": results,
+    })))
 }
 
 pub async fn set_keywords_to_stats(
     account: String,
     input: Vec<UpdateKeywordStat>,
     db: Db,
-) -> Result<impl warp::Reply, Infallible> {
-    let mut lock = db.write().await;
-
-    if let Some(stats) = lock.get_mut(&account) {
-        let main_stats = &mut stats.main_stats;
-
-        main_stats.last_updated_at = crate::helpers::current_time_string();
+) -> Result<impl warp::Reply, Rejection> {
+    let slot = db
+        .get(&account)
+        .ok_or_else(|| ApiError::AccountNotFound(account.clone()))?;
+    let mut statistics = metrics::write_account(&slot).await;
 
-        for ii in input.iter() {
-            KeywordStatistics::update(stats, ii)
-        }
-    }
+    commit(&mut statistics, Event::SetKeywords { account, input })
+        .await
+        .map_err(ApiError::from_journal)?;
 
     Ok(json(&json!({"type": "success",})))
 }
@@ -157,27 +725,94 @@ pub async fn add_logs_to_keyword(
     id: u64,
     input: Log,
     db: Db,
-) -> Result<impl warp::Reply, Infallible> {
-    let mut lock = db.write().await;
-
-    if let Some(stats) = lock.get_mut(&account) {
-        KeywordStatistics::add_logs(stats, id, input)
-    }
+) -> Result<impl warp::Reply, Rejection> {
+    let orphans = orphan_policy();
 
-    Ok(json(&json!({"type": "success",})))
+    let slot = match db.get(&account) {
+        Some(slot) => slot,
+        None if orphans == OrphanPolicy::Drop => {
+            return Err(ApiError::AccountNotFound(account).into())
+        }
+        None => db.get_or_create(&account),
+    };
+    let mut statistics = metrics::write_account(&slot).await;
+
+    let known = statistics.keyword_stats.contains_key(&id);
+
+    let status = match (known, orphans) {
+        (true, _) => "accepted",
+        (false, OrphanPolicy::Drop) => {
+            // Journaled only to count the dropped log
+            let event = Event::AddLogBatch {
+                account: account.clone(),
+                input: Vec::new(),
+                orphans,
+                dropped: 1,
+            };
+            commit(&mut statistics, event)
+                .await
+                .map_err(ApiError::from_journal)?;
+
+            return Err(ApiError::KeywordNotFound(account, id).into());
+        }
+        (false, OrphanPolicy::Create) => "created",
+        (false, OrphanPolicy::Buffer { .. }) => "buffered",
+    };
+
+    commit(
+        &mut statistics,
+        Event::AddKeywordLog {
+            account,
+            id,
+            input,
+            orphans,
+        },
+    )
+    .await
+    .map_err(ApiError::from_journal)?;
+
+    Ok(json(&json!({"type": "success", "status": status})))
 }
 
 pub async fn update_keyword_stat(
     account: String,
     input: UpdateKeywordStat,
     db: Db,
-) -> Result<impl warp::Reply, Infallible> {
-    let mut lock = db.write().await;
-    let stats = lock.get_mut(&account);
+) -> Result<impl warp::Reply, Rejection> {
+    let slot = db
+        .get(&account)
+        .ok_or_else(|| ApiError::AccountNotFound(account.clone()))?;
+    let mut statistics = metrics::write_account(&slot).await;
 
-    if let Some(stats) = stats {
-        KeywordStatistics::update(stats, &input);
-    }
+    commit(&mut statistics, Event::UpdateKeyword { account, input })
+        .await
+        .map_err(ApiError::from_journal)?;
 
     Ok(json(&json!({"type": "success"})))
 }
+
+pub async fn list_archives(account: String) -> Result<impl warp::Reply, Rejection> {
+    let files = archive::list(&account).map_err(|e| ApiError::Internal(e.to_string()))?;
+    Ok(json(&json!({ "archives": files })))
+}
+
+pub async fn download_archive(
+    account: String,
+    name: String,
+) -> Result<impl warp::Reply, Rejection> {
+    let content = match archive::path_of(&account, &name) {
+        Some(path) => tokio::fs::read(path).await.ok(),
+        None => None,
+    };
+
+    let content = content.ok_or(ApiError::ArchiveNotFound(name.clone()))?;
+
+    warp::http::Response::builder()
+        .header("content-type", "application/gzip")
+        .header(
+            "content-disposition",
+            format!("attachment; filename=\"{}\"", name),
+        )
+        .body(content)
+        .map_err(|e| ApiError::Internal(e.to_string()).into())
+}
@@ -0,0 +1,161 @@
+// Errors returned by api.
+// Handlers and filters reject with `ApiError` and `handle_rejection` turns every rejection
+// (ours and warp's own) into `{"type": "error", "code": .., "message": ..}` with proper status.
+use crate::models::KeywordId;
+use serde_json::json;
+use std::convert::Infallible;
+use tracing::error;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+#[derive(Debug, Clone)]
+pub enum ApiError {
+    AccountNotFound(String),
+    KeywordNotFound(String, KeywordId),
+    ArchiveNotFound(String),
+    IssueNotFound(String, String),
+
+    // Query or body which can't be parsed at all
+    BadRequest(String),
+
+    // Well formed body which doesn't match what endpoint expects
+    Unprocessable(String),
+
+    // Request doesn't fit current state e.g. ending a run which isn't open
+    Conflict(String),
+
+    // Body over `max_body_bytes`, compressed or decoded
+    PayloadTooLarge(String),
+
+    Unauthorized,
+    Forbidden,
+    RouteNotFound,
+    MethodNotAllowed,
+    Internal(String),
+
+    // Event couldn't be written to journal so it wasn't applied, safe to retry
+    Unavailable(String),
+}
+
+impl warp::reject::Reject for ApiError {}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::AccountNotFound(_)
+            | ApiError::KeywordNotFound(..)
+            | ApiError::ArchiveNotFound(_)
+            | ApiError::IssueNotFound(..)
+            | ApiError::RouteNotFound => StatusCode::NOT_FOUND,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+            ApiError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::AccountNotFound(_) => "account_not_found",
+            ApiError::KeywordNotFound(..) => "keyword_not_found",
+            ApiError::ArchiveNotFound(_) => "archive_not_found",
+            ApiError::IssueNotFound(..) => "issue_not_found",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unprocessable(_) => "unprocessable",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::Forbidden => "forbidden",
+            ApiError::RouteNotFound => "not_found",
+            ApiError::MethodNotAllowed => "method_not_allowed",
+            ApiError::Internal(_) => "internal",
+            ApiError::Unavailable(_) => "unavailable",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::AccountNotFound(account) => format!("Account {} not found", account),
+            ApiError::KeywordNotFound(account, id) => {
+                format!("Keyword {} not found in account {}", id, account)
+            }
+            ApiError::ArchiveNotFound(name) => format!("Archive {} not found", name),
+            ApiError::IssueNotFound(account, fingerprint) => {
+                format!("Issue {} not found in account {}", fingerprint, account)
+            }
+            ApiError::BadRequest(message)
+            | ApiError::Unprocessable(message)
+            | ApiError::Conflict(message)
+            | ApiError::PayloadTooLarge(message)
+            | ApiError::Internal(message)
+            | ApiError::Unavailable(message) => message.to_owned(),
+            ApiError::Unauthorized => "Missing or invalid api key".to_owned(),
+            ApiError::Forbidden => "Api key is not allowed to do this".to_owned(),
+            ApiError::RouteNotFound => "No such route".to_owned(),
+            ApiError::MethodNotAllowed => "Method not allowed".to_owned(),
+        }
+    }
+
+    // Body that was valid json but had wrong shape is 422, broken json is 400
+    pub fn from_json(e: serde_json::Error) -> ApiError {
+        match e.classify() {
+            serde_json::error::Category::Data => ApiError::Unprocessable(e.to_string()),
+            _ => ApiError::BadRequest(e.to_string()),
+        }
+    }
+
+    pub fn from_journal(e: std::io::Error) -> ApiError {
+        error!("Unable to write event to journal: {:?}", e);
+        ApiError::Unavailable("Unable to record change, try again later".to_owned())
+    }
+
+    pub fn into_response(self) -> warp::reply::Response {
+        let reply = warp::reply::with_status(
+            warp::reply::json(&json!({
+                "type": "error",
+                "code": self.code(),
+                "message": self.message(),
+            })),
+            self.status(),
+        );
+
+        match self {
+            ApiError::Unauthorized => {
+                warp::reply::with_header(reply, "www-authenticate", "Bearer").into_response()
+            }
+            _ => reply.into_response(),
+        }
+    }
+}
+
+impl From<ApiError> for Rejection {
+    fn from(e: ApiError) -> Rejection {
+        warp::reject::custom(e)
+    }
+}
+
+pub async fn handle_rejection(rejection: Rejection) -> Result<warp::reply::Response, Infallible> {
+    let error = if let Some(e) = rejection.find::<ApiError>() {
+        e.clone()
+    } else if rejection.is_not_found() {
+        ApiError::RouteNotFound
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        ApiError::MethodNotAllowed
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidQuery>() {
+        ApiError::BadRequest(e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::MissingHeader>() {
+        ApiError::BadRequest(e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidHeader>() {
+        ApiError::BadRequest(e.to_string())
+    } else {
+        error!("Unhandled rejection {:?}", rejection);
+        ApiError::Internal("Internal server error".to_owned())
+    };
+
+    Ok(error.into_response())
+}
@@ -0,0 +1,293 @@
+// Live feeds.
+// Every log stored by `add_logs_to_stats` or `KeywordStatistics::add_logs` is published here
+// and pushed to `/{account}/logs/tail` subscribers as server sent events.
+// Field level changes of MainStats and KeywordStat are pushed to `/{account}/stats/ws`.
+use crate::log_buffer::LogBuffer;
+use crate::models::{Account, Event, KeywordId, Log, MainStats, Statistics};
+use crate::severity::Severity;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use tokio::sync::broadcast;
+
+// Slow subscribers which fall behind this many events are disconnected and
+// have to resume using last event id.
+const LOG_CHANNEL_CAPACITY: usize = 4096;
+
+static LOG_EVENTS: Lazy<broadcast::Sender<LogEvent>> =
+    Lazy::new(|| broadcast::channel(LOG_CHANNEL_CAPACITY).0);
+
+const STATS_CHANNEL_CAPACITY: usize = 4096;
+
+static STATS_EVENTS: Lazy<broadcast::Sender<StatsMessage>> =
+    Lazy::new(|| broadcast::channel(STATS_CHANNEL_CAPACITY).0);
+
+#[derive(Debug, Serialize, Clone)]
+pub struct LogEvent {
+    pub account: Account,
+    // None for main logs
+    pub keyword_id: Option<KeywordId>,
+    #[serde(flatten)]
+    pub log: Log,
+}
+
+pub fn publish_log(account: &str, keyword_id: Option<KeywordId>, log: &Log) {
+    if LOG_EVENTS.receiver_count() == 0 {
+        return;
+    }
+
+    let _ = LOG_EVENTS.send(LogEvent {
+        account: account.to_owned(),
+        keyword_id,
+        log: log.clone(),
+    });
+}
+
+pub fn subscribe_logs() -> broadcast::Receiver<LogEvent> {
+    LOG_EVENTS.subscribe()
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TailQuery {
+    pub keyword_id: Option<KeywordId>,
+
+    // Comma separated list of log types
+    pub r#type: Option<String>,
+
+    // Only logs at least this severe
+    pub min_severity: Option<String>,
+
+    // Resume after this sequence number. `Last-Event-ID` header takes precedence.
+    pub since: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TailFilter {
+    pub account: Account,
+    pub keyword_id: Option<KeywordId>,
+    pub types: Option<Vec<Severity>>,
+    pub min_level: Option<u8>,
+}
+
+impl TailFilter {
+    pub fn new(account: Account, query: &TailQuery) -> Result<Self, String> {
+        Ok(TailFilter {
+            account,
+            keyword_id: query.keyword_id,
+            types: query.r#type.as_deref().map(Severity::parse_list),
+            min_level: query
+                .min_severity
+                .as_deref()
+                .map(Severity::parse_min)
+                .transpose()?,
+        })
+    }
+
+    fn matches_log(&self, keyword_id: Option<KeywordId>, log: &Log) -> bool {
+        if self.keyword_id.is_some() && self.keyword_id != keyword_id {
+            return false;
+        }
+
+        if let Some(min_level) = self.min_level {
+            if log.r#type.level() < min_level {
+                return false;
+            }
+        }
+
+        match &self.types {
+            Some(types) => types.contains(&log.r#type),
+            None => true,
+        }
+    }
+
+    pub fn matches(&self, event: &LogEvent) -> bool {
+        event.account == self.account && self.matches_log(event.keyword_id, &event.log)
+    }
+
+    // Stored logs newer than `since`, oldest first
+    pub fn backlog(&self, statistics: &Statistics, since: u64) -> Vec<LogEvent> {
+        let mut events = Vec::new();
+
+        let mut push = |keyword_id: Option<KeywordId>, logs: &LogBuffer| {
+            for log in logs.iter().filter(|log| log.seq > since) {
+                if self.matches_log(keyword_id, log) {
+                    events.push(LogEvent {
+                        account: self.account.clone(),
+                        keyword_id,
+                        log: log.clone(),
+                    });
+                }
+            }
+        };
+
+        if self.keyword_id.is_none() {
+            push(None, &statistics.main_stats.logs);
+        }
+
+        for (id, ks) in statistics.keyword_stats.iter() {
+            push(Some(*id), &ks.keyword_logs);
+        }
+
+        events.sort_by_key(|e| e.log.seq);
+        events
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StatsMessage {
+    // Sent once on connect and again if client falls behind
+    Snapshot {
+        account: Account,
+        main_stats: Value,
+        keyword_stats: Vec<Value>,
+    },
+    MainStats {
+        account: Account,
+        changes: Map<String, Value>,
+    },
+    // New keywords are sent with all their fields as changes
+    KeywordStat {
+        account: Account,
+        id: KeywordId,
+        changes: Map<String, Value>,
+    },
+}
+
+impl StatsMessage {
+    pub fn account(&self) -> &str {
+        match self {
+            StatsMessage::Snapshot { account, .. }
+            | StatsMessage::MainStats { account, .. }
+            | StatsMessage::KeywordStat { account, .. } => account,
+        }
+    }
+}
+
+pub fn subscribe_stats() -> broadcast::Receiver<StatsMessage> {
+    STATS_EVENTS.subscribe()
+}
+
+// Logs are not part of stats feed. Leaving them out before serializing
+// is much cheaper than serializing them and throwing them away.
+pub fn main_stats_fields(main_stats: &MainStats) -> Map<String, Value> {
+    match serde_json::to_value(main_stats.without_logs()) {
+        Ok(Value::Object(mut map)) => {
+            map.remove("logs");
+            map
+        }
+        _ => Map::new(),
+    }
+}
+
+pub fn to_fields<T: Serialize>(value: &T) -> Map<String, Value> {
+    match serde_json::to_value(value) {
+        Ok(Value::Object(map)) => map,
+        _ => Map::new(),
+    }
+}
+
+// Only taken when client connects so here we can afford serializing logs.
+pub fn snapshot(statistics: &Statistics) -> StatsMessage {
+    let mut main_stats = to_fields(&statistics.main_stats);
+    main_stats.remove("logs");
+
+    StatsMessage::Snapshot {
+        account: statistics.main_stats.account_name.clone(),
+        main_stats: Value::Object(main_stats),
+        keyword_stats: statistics
+            .keyword_stats
+            .values()
+            .map(|ks| Value::Object(to_fields(&ks.stats)))
+            .collect(),
+    }
+}
+
+// State of the parts of an account which an event can change
+pub struct Captured {
+    main_stats: Map<String, Value>,
+    keyword_stats: HashMap<KeywordId, Map<String, Value>>,
+}
+
+// Keywords whose stats an event may change
+pub fn keyword_ids(event: &Event, statistics: &Statistics) -> Vec<KeywordId> {
+    match event {
+        Event::UpdateStats { .. }
+        | Event::AddLog { .. }
+        | Event::StartRun { .. }
+        | Event::EndRun { .. }
+        | Event::Expire { .. } => Vec::new(),
+        Event::SetKeywords { input, .. } => input.iter().map(|k| k.id).collect(),
+        Event::AddKeywordLog { id, .. } => vec![*id],
+        Event::AddLogBatch { input, .. } => {
+            let mut ids: Vec<KeywordId> = input.iter().filter_map(|l| l.keyword_id).collect();
+            ids.sort_unstable();
+            ids.dedup();
+            ids
+        }
+        Event::UpdateKeyword { input, .. } => vec![input.id],
+        Event::Heartbeat { input, .. } => input.keywords.clone(),
+        Event::Clear { .. } | Event::Resize { .. } => {
+            statistics.keyword_stats.keys().copied().collect()
+        }
+    }
+}
+
+fn capture_statistics(event: &Event, statistics: &Statistics) -> Captured {
+    let keyword_stats = keyword_ids(event, statistics)
+        .into_iter()
+        .filter_map(|id| {
+            statistics
+                .keyword_stats
+                .get(&id)
+                .map(|ks| (id, to_fields(&ks.stats)))
+        })
+        .collect();
+
+    Captured {
+        main_stats: main_stats_fields(&statistics.main_stats),
+        keyword_stats,
+    }
+}
+
+// Called before event is applied. Returns None when nobody is listening.
+pub fn capture(statistics: &Statistics, event: &Event) -> Option<Captured> {
+    if STATS_EVENTS.receiver_count() == 0 {
+        return None;
+    }
+
+    Some(capture_statistics(event, statistics))
+}
+
+fn changed_fields(before: Option<&Map<String, Value>>, after: Map<String, Value>) -> Map<String, Value> {
+    after
+        .into_iter()
+        .filter(|(key, value)| before.and_then(|b| b.get(key)) != Some(value))
+        .collect()
+}
+
+// Called after event is applied. Publishes whatever changed since `capture`.
+pub fn publish_changes(statistics: &Statistics, event: &Event, before: Captured) {
+    let account = statistics.main_stats.account_name.clone();
+    let after = capture_statistics(event, statistics);
+
+    let changes = changed_fields(Some(&before.main_stats), after.main_stats);
+    if !changes.is_empty() {
+        let _ = STATS_EVENTS.send(StatsMessage::MainStats {
+            account: account.clone(),
+            changes,
+        });
+    }
+
+    for (id, fields) in after.keyword_stats {
+        let changes = changed_fields(before.keyword_stats.get(&id), fields);
+        if !changes.is_empty() {
+            let _ = STATS_EVENTS.send(StatsMessage::KeywordStat {
+                account: account.clone(),
+                id,
+                changes,
+            });
+        }
+    }
+}
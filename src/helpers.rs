@@ -1,7 +1,23 @@
-pub fn current_time_string() -> String {
-    time::OffsetDateTime::now_local()
-        .format("%F %r")
-        .to_string()
+// Sanitized names are kept this short in file names
+const MAX_PREFIX_CHARS: usize = 64;
+
+// File name for something named by users, like an account. `sanitize` alone maps different
+// names to the same file ("shop:1" and "shop_1") and keeps names too long for the file system,
+// so a hash of the whole name follows a shortened sanitized one.
+pub fn file_stem(name: &str) -> String {
+    format!("{}-{:016x}", short_sanitize(name), fnv1a(name.as_bytes()))
+}
+
+// Sanitized and cut to `MAX_PREFIX_CHARS`, for names which are already unique within a directory
+pub fn short_sanitize(name: &str) -> String {
+    sanitize(name).chars().take(MAX_PREFIX_CHARS).collect()
+}
+
+// FNV-1a, unlike std hashers it is stable across Rust versions so file names stay the same
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
 }
 
 pub fn sanitize(s: &str) -> String {
@@ -96,3 +112,22 @@ pub fn sanitize(s: &str) -> String {
         .trim_matches(|c: char| c.is_whitespace() || c == '_' || c == '-')
         .to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_stems_are_distinct_and_short() {
+        assert_eq!(sanitize("shop:1"), sanitize("shop_1"));
+        assert_ne!(file_stem("shop:1"), file_stem("shop_1"));
+        assert!(file_stem("shop:1").starts_with("shop_1-"));
+
+        let long = "é".repeat(300);
+        assert!(file_stem(&long).len() < 255);
+        assert_ne!(file_stem(&long), file_stem(&(long.clone() + "x")));
+
+        // Stays the same across builds, snapshots are found by it
+        assert_eq!(file_stem(""), "-cbf29ce484222325");
+    }
+}
@@ -0,0 +1,201 @@
+// History of a keyword's bid: current price, ad position and placement.
+// A point is recorded whenever one of them changes. Points are kept raw for a day, at most
+// `MAX_RAW_POINTS` of them, and then merged into 5 minute buckets which are kept for 30 days.
+// Compaction runs when a point is recorded and from the retention sweep for keywords which went
+// quiet. Points are merged oldest first into the bucket of their own time, so the result doesn't
+// depend on when compaction ran and journal replay ends with the same history.
+use crate::models::KeywordStat;
+use crate::timestamp::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+const RAW_SECS: i64 = 24 * 60 * 60;
+const BUCKET_SECS: i64 = 5 * 60;
+const KEEP_SECS: i64 = 30 * 24 * 60 * 60;
+
+// Raw points of a keyword whose price changes very often are merged early
+const MAX_RAW_POINTS: usize = 2000;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub time: Timestamp,
+    pub current_price: Option<f64>,
+    pub ads_position: Option<u64>,
+    pub placement: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub struct Summary {
+    pub min: f64,
+    pub max: f64,
+    // Value at end of bucket
+    pub last: f64,
+}
+
+impl Summary {
+    fn merge(summary: Option<Summary>, value: Option<f64>) -> Option<Summary> {
+        match (summary, value) {
+            (Some(s), Some(v)) => Some(Summary {
+                min: s.min.min(v),
+                max: s.max.max(v),
+                last: v,
+            }),
+            (None, Some(v)) => Some(Summary {
+                min: v,
+                max: v,
+                last: v,
+            }),
+            (s, None) => s,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Bucket {
+    pub start: Timestamp,
+    // Number of changes merged into bucket
+    pub changes: u32,
+    pub current_price: Option<Summary>,
+    pub ads_position: Option<Summary>,
+    pub placement: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct History {
+    // Oldest first
+    pub raw: VecDeque<Point>,
+    pub buckets: VecDeque<Bucket>,
+}
+
+fn bucket_start(time: Timestamp) -> Timestamp {
+    time.floor_millis(BUCKET_SECS * 1000)
+}
+
+impl History {
+    fn latest(&self) -> Option<(Option<f64>, Option<u64>, Option<u64>)> {
+        if let Some(p) = self.raw.back() {
+            return Some((p.current_price, p.ads_position, p.placement));
+        }
+
+        self.buckets.back().map(|b| {
+            (
+                b.current_price.map(|s| s.last),
+                b.ads_position.map(|s| s.last as u64),
+                b.placement,
+            )
+        })
+    }
+
+    // Records stat as it is after an update, if anything tracked changed
+    pub fn record(&mut self, stat: &KeywordStat, time: Timestamp) {
+        // Compacted first so `latest` is the same whether or not retention sweep compacted
+        // history already, replay doesn't see the sweeps
+        self.compact(time);
+
+        let current = (stat.current_price, stat.ads_position, stat.placement);
+
+        if self.latest() != Some(current) && current != (None, None, None) {
+            self.raw.push_back(Point {
+                time,
+                current_price: stat.current_price,
+                ads_position: stat.ads_position,
+                placement: stat.placement,
+            });
+        }
+
+        while self.raw.len() > MAX_RAW_POINTS {
+            self.merge_oldest();
+        }
+    }
+
+    pub fn needs_compaction(&self, now: Timestamp) -> bool {
+        let raw_cutoff = now.add_secs(-RAW_SECS);
+        let keep_cutoff = now.add_secs(-KEEP_SECS);
+
+        self.raw.len() > MAX_RAW_POINTS
+            || self.raw.front().is_some_and(|p| p.time < raw_cutoff)
+            || self.buckets.front().is_some_and(|b| b.start < keep_cutoff)
+    }
+
+    pub fn compact(&mut self, now: Timestamp) {
+        let raw_cutoff = now.add_secs(-RAW_SECS);
+        while self.raw.len() > MAX_RAW_POINTS
+            || self.raw.front().is_some_and(|p| p.time < raw_cutoff)
+        {
+            self.merge_oldest();
+        }
+
+        let keep_cutoff = now.add_secs(-KEEP_SECS);
+        while self.buckets.front().is_some_and(|b| b.start < keep_cutoff) {
+            self.buckets.pop_front();
+        }
+    }
+
+    // Moves oldest raw point into the bucket of its time
+    fn merge_oldest(&mut self) {
+        let point = match self.raw.pop_front() {
+            Some(point) => point,
+            None => return,
+        };
+        let start = bucket_start(point.time);
+
+        if self.buckets.back().is_none_or(|b| b.start != start) {
+            self.buckets.push_back(Bucket {
+                start,
+                changes: 0,
+                current_price: None,
+                ads_position: None,
+                placement: None,
+            });
+        }
+
+        let bucket = self.buckets.back_mut().unwrap();
+        bucket.changes += 1;
+        bucket.current_price = Summary::merge(bucket.current_price, point.current_price);
+        bucket.ads_position =
+            Summary::merge(bucket.ads_position, point.ads_position.map(|p| p as f64));
+        bucket.placement = point.placement.or(bucket.placement);
+    }
+}
+
+// `?from=..&to=..` of `/{account}/keywords/{id}/history`. Same time formats as log queries.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct HistoryQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryPage<'a> {
+    pub keyword_id: u64,
+    pub bucket_secs: i64,
+    // Older than a day, 5 minute buckets
+    pub buckets: Vec<&'a Bucket>,
+    // Last day, every change up to `MAX_RAW_POINTS`
+    pub raw: Vec<&'a Point>,
+}
+
+impl HistoryQuery {
+    fn range(&self) -> Result<(Option<Timestamp>, Option<Timestamp>), String> {
+        let parse = |value: &Option<String>, name: &str| match value {
+            Some(v) => Timestamp::parse(v)
+                .map(Some)
+                .ok_or_else(|| format!("Invalid {} time {}", name, v)),
+            None => Ok(None),
+        };
+
+        Ok((parse(&self.from, "from")?, parse(&self.to, "to")?))
+    }
+
+    pub fn run<'a>(&self, keyword_id: u64, history: &'a History) -> Result<HistoryPage<'a>, String> {
+        let (from, to) = self.range()?;
+        let within = |time: Timestamp| from.is_none_or(|f| time >= f) && to.is_none_or(|t| time <= t);
+
+        Ok(HistoryPage {
+            keyword_id,
+            bucket_secs: BUCKET_SECS,
+            buckets: history.buckets.iter().filter(|b| within(b.start)).collect(),
+            raw: history.raw.iter().filter(|p| within(p.time)).collect(),
+        })
+    }
+}
@@ -0,0 +1,210 @@
+// Grouping of error logs into issues.
+// Message of every error log is normalized (urls, ids and numbers replaced by placeholders) and
+// logs with the same normalized message belong to the same issue. The fingerprint of an issue is
+// crc32 of its normalized message so it stays the same across restarts.
+// Only `MAX_ISSUES` issues are kept per account, least recently seen ones are forgotten first.
+use crate::models::{KeywordId, Log};
+use crate::severity::Severity;
+use crate::timestamp::Timestamp;
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::cmp::Reverse;
+
+const MAX_ISSUES: usize = 1000;
+const MAX_KEYWORDS_PER_ISSUE: usize = 1000;
+const MAX_TITLE_CHARS: usize = 300;
+
+const DEFAULT_LIMIT: usize = 50;
+
+// Order matters, urls may contain ids and ids may contain numbers.
+// `PATTERNS` go first, then `HEX_ID` and `NUMBERS`.
+static PATTERNS: Lazy<Vec<(Regex, &'static str)>> = Lazy::new(|| {
+    vec![
+        (Regex::new(r"[a-zA-Z][a-zA-Z0-9+.-]*://\S+").unwrap(), "<url>"),
+        // uuids like request ids
+        (
+            Regex::new(r"\b[0-9a-fA-F]{8}(-[0-9a-fA-F]{4}){3}-[0-9a-fA-F]{12}\b").unwrap(),
+            "<id>",
+        ),
+    ]
+});
+
+// Long hex strings. Plain decimal runs also match, those are left to `NUMBERS` so
+// "keyword 10000000" is the same issue as "keyword 9999999".
+static HEX_ID: Lazy<Regex> = Lazy::new(|| Regex::new(r"\b(0x)?[0-9a-fA-F]{8,}\b").unwrap());
+
+static NUMBERS: Lazy<Vec<(Regex, &'static str)>> = Lazy::new(|| {
+    vec![
+        (Regex::new(r"\d+(\.\d+)?").unwrap(), "<n>"),
+        (Regex::new(r"\s+").unwrap(), " "),
+    ]
+});
+
+pub fn normalize(message: &str) -> String {
+    let mut normalized = message.trim().to_owned();
+    for (pattern, replacement) in PATTERNS.iter() {
+        normalized = pattern.replace_all(&normalized, *replacement).into_owned();
+    }
+
+    normalized = HEX_ID
+        .replace_all(&normalized, |caps: &Captures| {
+            if caps[0].bytes().all(|b| b.is_ascii_digit()) {
+                caps[0].to_owned()
+            } else {
+                "<id>".to_owned()
+            }
+        })
+        .into_owned();
+
+    for (pattern, replacement) in NUMBERS.iter() {
+        normalized = pattern.replace_all(&normalized, *replacement).into_owned();
+    }
+
+    normalized.chars().take(MAX_TITLE_CHARS).collect()
+}
+
+pub fn fingerprint(normalized: &str) -> String {
+    format!("{:08x}", crc32fast::hash(normalized.as_bytes()))
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Issue {
+    pub fingerprint: String,
+    // Normalized message
+    pub title: String,
+    // Latest message as it was sent
+    pub last_message: String,
+    pub severity: Severity,
+    pub first_seen: Timestamp,
+    pub last_seen: Timestamp,
+    pub count: u64,
+    // Errors which were main logs
+    pub main_count: u64,
+    pub keyword_ids: BTreeSet<KeywordId>,
+}
+
+// Keyed by fingerprint
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct Issues(HashMap<String, Issue>);
+
+impl Issues {
+    // Called for every log added, only errors are recorded
+    pub fn record(&mut self, log: &Log, keyword_id: Option<KeywordId>) {
+        if !log.r#type.is_error() {
+            return;
+        }
+
+        let title = normalize(&log.message);
+        let fingerprint = fingerprint(&title);
+        let time = log.received_at;
+
+        if !self.0.contains_key(&fingerprint) && self.0.len() >= MAX_ISSUES {
+            self.forget_oldest();
+        }
+
+        let issue = self.0.entry(fingerprint.clone()).or_insert_with(|| Issue {
+            fingerprint,
+            title,
+            last_message: String::new(),
+            severity: log.r#type.clone(),
+            first_seen: time,
+            last_seen: time,
+            count: 0,
+            main_count: 0,
+            keyword_ids: BTreeSet::new(),
+        });
+
+        issue.count += 1;
+        issue.last_seen = issue.last_seen.max(time);
+        issue.last_message = log.message.clone();
+        if log.r#type.level() > issue.severity.level() {
+            issue.severity = log.r#type.clone();
+        }
+
+        match keyword_id {
+            Some(id) if issue.keyword_ids.len() < MAX_KEYWORDS_PER_ISSUE => {
+                issue.keyword_ids.insert(id);
+            }
+            Some(_) => {}
+            None => issue.main_count += 1,
+        }
+    }
+
+    fn forget_oldest(&mut self) {
+        let oldest = self
+            .0
+            .values()
+            .min_by_key(|issue| issue.last_seen)
+            .map(|issue| issue.fingerprint.clone());
+
+        if let Some(fingerprint) = oldest {
+            self.0.remove(&fingerprint);
+        }
+    }
+
+    pub fn get(&self, fingerprint: &str) -> Option<&Issue> {
+        self.0.get(fingerprint)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueSort {
+    LastSeen,
+    FirstSeen,
+    Count,
+}
+
+// `?sort=..&since=..&keyword_id=..&limit=..` of `/{account}/issues`
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct IssuesQuery {
+    // last_seen by default, always newest or biggest first
+    pub sort: Option<IssueSort>,
+    // Only issues seen at or after this time
+    pub since: Option<String>,
+    pub keyword_id: Option<KeywordId>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IssuesPage<'a> {
+    pub total: usize,
+    pub issues: Vec<&'a Issue>,
+}
+
+impl IssuesQuery {
+    pub fn run<'a>(&self, issues: &'a Issues) -> Result<IssuesPage<'a>, String> {
+        let since = match &self.since {
+            Some(s) => {
+                Some(Timestamp::parse(s).ok_or_else(|| format!("Invalid since time {}", s))?)
+            }
+            None => None,
+        };
+
+        let mut matching: Vec<&Issue> = issues
+            .0
+            .values()
+            .filter(|issue| since.is_none_or(|s| issue.last_seen >= s))
+            .filter(|issue| {
+                self.keyword_id
+                    .is_none_or(|id| issue.keyword_ids.contains(&id))
+            })
+            .collect();
+
+        match self.sort.unwrap_or(IssueSort::LastSeen) {
+            IssueSort::LastSeen => matching.sort_by_key(|issue| Reverse(issue.last_seen)),
+            IssueSort::FirstSeen => matching.sort_by_key(|issue| Reverse(issue.first_seen)),
+            IssueSort::Count => matching.sort_by_key(|issue| Reverse(issue.count)),
+        }
+
+        let total = matching.len();
+        matching.truncate(self.limit.unwrap_or(DEFAULT_LIMIT));
+
+        Ok(IssuesPage {
+            total,
+            issues: matching,
+        })
+    }
+}
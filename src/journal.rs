@@ -0,0 +1,564 @@
+// Append only journal of every mutation.
+// Each line is `<crc32 of json in hex> <json entry>`. If the host dies in the middle of a
+// write the last line fails checksum and everything after it is ignored on replay.
+//
+// Files are owned by a dedicated writer thread so writes and fsync never block the async
+// executor. `append` hands the entry over and waits until it is on disk. Whatever queued up
+// while the writer was busy is written with one write and one fsync (group commit).
+//
+// Journal is split in segments `journal.<first seq>.log`. Each flush starts a new segment and
+// once snapshots are written the older segments are deleted, so compaction never rewrites a
+// file writers are waiting on. `journal.log` of older versions is read as the first segment.
+use crate::models::Event;
+use crate::timestamp::Timestamp;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info};
+
+const LEGACY_FILE: &str = "journal.log";
+const SEGMENT_PREFIX: &str = "journal.";
+const SEGMENT_EXTENSION: &str = ".log";
+
+// Most entries written by one group commit
+const MAX_GROUP: usize = 512;
+
+static WRITER: OnceCell<mpsc::UnboundedSender<Request>> = OnceCell::new();
+
+// Seq of last entry which is on disk
+static LAST_SEQ: AtomicU64 = AtomicU64::new(0);
+
+struct Append {
+    time: Timestamp,
+    event: Event,
+    reply: oneshot::Sender<io::Result<u64>>,
+}
+
+enum Request {
+    Append(Append),
+    Rotate {
+        reply: oneshot::Sender<io::Result<u64>>,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Entry {
+    pub seq: u64,
+    pub time: Timestamp,
+    pub event: Event,
+}
+
+pub struct Journal {
+    dir: PathBuf,
+    fsync: bool,
+    last_seq: u64,
+
+    // Segment being appended to
+    path: PathBuf,
+    file: File,
+    // Bytes of complete entries in segment. File is cut back to this after a failed write.
+    len: u64,
+    torn: bool,
+}
+
+impl Journal {
+    // Opens journal inside data directory and returns every valid entry in it.
+    // Torn tails are cut off and new entries go to a fresh segment after last valid one.
+    pub fn open(data_dir: &Path, fsync: bool) -> io::Result<(Journal, Vec<Entry>)> {
+        let mut entries = Vec::new();
+
+        for (_, path) in segments(data_dir)? {
+            let (mut valid, valid_len) = read_entries(&path)?;
+
+            let file = OpenOptions::new().write(true).open(&path)?;
+            if file.metadata()?.len() > valid_len {
+                error!(
+                    "Journal {:?} has corrupt tail, truncating it to {} bytes",
+                    path, valid_len
+                );
+                file.set_len(valid_len)?;
+            }
+
+            entries.append(&mut valid);
+        }
+
+        let last_seq = entries.last().map(|e| e.seq).unwrap_or(0);
+        let (path, file, len) = open_segment(data_dir, last_seq + 1)?;
+
+        Ok((
+            Journal {
+                dir: data_dir.to_owned(),
+                fsync,
+                last_seq,
+                path,
+                file,
+                len,
+                torn: false,
+            },
+            entries,
+        ))
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    // Writes events as consecutive entries with a single write and fsync.
+    // Returns seq of the first one. On error none of them is in journal.
+    pub fn append(&mut self, events: Vec<(Timestamp, Event)>) -> io::Result<u64> {
+        let first = self.last_seq + 1;
+        let mut last = self.last_seq;
+
+        let mut lines = Vec::new();
+        for (time, event) in events {
+            last += 1;
+            let entry = Entry {
+                seq: last,
+                time,
+                event,
+            };
+            lines.extend_from_slice(&encode(&entry)?);
+        }
+
+        self.write(&lines)?;
+
+        self.last_seq = last;
+        self.len += lines.len() as u64;
+        Ok(first)
+    }
+
+    fn write(&mut self, lines: &[u8]) -> io::Result<()> {
+        // Part of an earlier failed write may still be there, nothing goes after it
+        if self.torn {
+            self.file.set_len(self.len)?;
+            self.torn = false;
+        }
+
+        let result = self.file.write_all(lines).and_then(|_| match self.fsync {
+            true => self.file.sync_data(),
+            false => Ok(()),
+        });
+
+        if result.is_err() {
+            if let Err(e) = self.file.set_len(self.len) {
+                error!("Unable to cut failed write from {:?}: {:?}", self.path, e);
+                self.torn = true;
+            }
+        }
+
+        result
+    }
+
+    // Starts a new segment. Returns seq of last entry in older segments.
+    pub fn rotate(&mut self) -> io::Result<u64> {
+        if self.len == 0 {
+            return Ok(self.last_seq);
+        }
+
+        if self.fsync {
+            self.file.sync_data()?;
+        }
+
+        let (path, file, len) = open_segment(&self.dir, self.last_seq + 1)?;
+        self.path = path;
+        self.file = file;
+        self.len = len;
+        self.torn = false;
+
+        Ok(self.last_seq)
+    }
+}
+
+fn segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!(
+        "{}{:020}{}",
+        SEGMENT_PREFIX, first_seq, SEGMENT_EXTENSION
+    ))
+}
+
+fn open_segment(dir: &Path, first_seq: u64) -> io::Result<(PathBuf, File, u64)> {
+    let path = segment_path(dir, first_seq);
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let len = file.metadata()?.len();
+    Ok((path, file, len))
+}
+
+// Segments in data directory with their first seq, oldest first
+fn segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        let first_seq = if name == LEGACY_FILE {
+            Some(0)
+        } else {
+            name.strip_prefix(SEGMENT_PREFIX)
+                .and_then(|rest| rest.strip_suffix(SEGMENT_EXTENSION))
+                .and_then(|seq| seq.parse().ok())
+        };
+
+        if let Some(first_seq) = first_seq {
+            segments.push((first_seq, entry.path()));
+        }
+    }
+
+    segments.sort();
+    Ok(segments)
+}
+
+fn encode(entry: &Entry) -> io::Result<Vec<u8>> {
+    let json = serde_json::to_vec(entry)?;
+    let mut line = format!("{:08x} ", crc32fast::hash(&json)).into_bytes();
+    line.extend_from_slice(&json);
+    line.push(b'\n');
+    Ok(line)
+}
+
+fn decode(line: &str) -> Option<Entry> {
+    let mut parts = line.splitn(2, ' ');
+    let crc = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let json = parts.next()?;
+
+    if crc32fast::hash(json.as_bytes()) != crc {
+        return None;
+    }
+
+    serde_json::from_str(json).ok()
+}
+
+// Returns valid entries and byte length of valid prefix of file
+fn read_entries(path: &Path) -> io::Result<(Vec<Entry>, u64)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    let mut valid_len = 0;
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        let read = match reader.read_line(&mut line) {
+            Ok(read) => read,
+            // Invalid utf8 can only come from a torn write
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => break,
+            Err(e) => return Err(e),
+        };
+
+        if read == 0 || !line.ends_with('\n') {
+            break;
+        }
+
+        match decode(line.trim_end_matches('\n')) {
+            Some(entry) => {
+                valid_len += read as u64;
+                entries.push(entry);
+            }
+            None => break,
+        }
+    }
+
+    Ok((entries, valid_len))
+}
+
+fn write_group(journal: &mut Journal, group: Vec<Append>) {
+    if group.is_empty() {
+        return;
+    }
+
+    let (events, replies): (Vec<_>, Vec<_>) = group
+        .into_iter()
+        .map(|append| ((append.time, append.event), append.reply))
+        .unzip();
+
+    match journal.append(events) {
+        Ok(first) => {
+            LAST_SEQ.store(journal.last_seq(), Ordering::SeqCst);
+            for (n, reply) in replies.into_iter().enumerate() {
+                let _ = reply.send(Ok(first + n as u64));
+            }
+        }
+        Err(e) => {
+            for reply in replies {
+                let _ = reply.send(Err(io::Error::new(e.kind(), e.to_string())));
+            }
+        }
+    }
+}
+
+fn run_writer(mut journal: Journal, mut requests: mpsc::UnboundedReceiver<Request>) {
+    while let Some(request) = futures::executor::block_on(requests.recv()) {
+        let mut batch = vec![request];
+        while batch.len() < MAX_GROUP {
+            match requests.try_recv() {
+                Ok(request) => batch.push(request),
+                Err(_) => break,
+            }
+        }
+
+        let mut group = Vec::with_capacity(batch.len());
+        for request in batch {
+            match request {
+                Request::Append(append) => group.push(append),
+                Request::Rotate { reply } => {
+                    // Entries sent before rotate belong to the old segment
+                    write_group(&mut journal, std::mem::take(&mut group));
+                    let _ = reply.send(journal.rotate());
+                }
+            }
+        }
+
+        write_group(&mut journal, group);
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("journal writer stopped")
+}
+
+// Opens journal and starts writer thread which serves `append`.
+pub fn init(data_dir: &Path, fsync: bool) -> io::Result<Vec<Entry>> {
+    let (journal, entries) = Journal::open(data_dir, fsync)?;
+    info!(
+        "Journal opened with {} entries, last seq {}",
+        entries.len(),
+        journal.last_seq()
+    );
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    if WRITER.set(tx).is_err() {
+        error!("Journal was already initialized");
+        return Ok(entries);
+    }
+
+    LAST_SEQ.store(journal.last_seq(), Ordering::SeqCst);
+    std::thread::Builder::new()
+        .name("journal".to_owned())
+        .spawn(move || run_writer(journal, rx))?;
+
+    Ok(entries)
+}
+
+async fn request<T>(
+    make: impl FnOnce(oneshot::Sender<io::Result<T>>) -> Request,
+) -> Option<io::Result<T>> {
+    let writer = WRITER.get()?;
+
+    let (reply, done) = oneshot::channel();
+    if writer.send(make(reply)).is_err() {
+        return Some(Err(stopped()));
+    }
+
+    Some(done.await.unwrap_or_else(|_| Err(stopped())))
+}
+
+// Writes event to journal and waits until it is written. Event must not be applied if this
+// fails, replay would never see it. Does nothing if journal was never initialized.
+pub async fn append(time: Timestamp, event: &Event) -> io::Result<()> {
+    let event = event.clone();
+    match request(|reply| Request::Append(Append { time, event, reply })).await {
+        Some(result) => result.map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+pub fn last_seq() -> u64 {
+    LAST_SEQ.load(Ordering::SeqCst)
+}
+
+// Closes current segment. Returns seq of last entry in closed segments, everything after it
+// goes to the new one.
+pub async fn rotate() -> io::Result<u64> {
+    request(|reply| Request::Rotate { reply })
+        .await
+        .unwrap_or(Ok(0))
+}
+
+// Deletes segments whose entries are all part of snapshots (seq <= upto).
+// Segment being written is never touched so this doesn't wait on writer.
+pub fn compact(data_dir: &Path, upto: u64) -> io::Result<()> {
+    let segments = segments(data_dir)?;
+
+    for pair in segments.windows(2) {
+        let (_, path) = &pair[0];
+        let (next_first_seq, _) = pair[1];
+
+        if next_first_seq.saturating_sub(1) <= upto {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Fresh directory per test so tests can run in parallel
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("journal-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn log(message: &str) -> (Timestamp, Event) {
+        let event = json!({
+            "event": "AddLog",
+            "account": "shop",
+            "input": {"type": "info", "message": message},
+        });
+        (Timestamp::now(), serde_json::from_value(event).unwrap())
+    }
+
+    fn messages(entries: &[Entry]) -> Vec<(u64, String)> {
+        entries
+            .iter()
+            .map(|entry| match &entry.event {
+                Event::AddLog { input, .. } => (entry.seq, input.message.clone()),
+                other => panic!("unexpected event {:?}", other),
+            })
+            .collect()
+    }
+
+    fn segment_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = segments(dir)
+            .unwrap()
+            .into_iter()
+            .map(|(_, path)| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn entries_survive_reopen() {
+        let dir = temp_dir("reopen");
+
+        let (mut journal, entries) = Journal::open(&dir, false).unwrap();
+        assert!(entries.is_empty());
+        assert_eq!(journal.append(vec![log("a"), log("b")]).unwrap(), 1);
+        assert_eq!(journal.append(vec![log("c")]).unwrap(), 3);
+        drop(journal);
+
+        let (mut journal, entries) = Journal::open(&dir, true).unwrap();
+        let expected = vec![
+            (1, "a".to_owned()),
+            (2, "b".to_owned()),
+            (3, "c".to_owned()),
+        ];
+        assert_eq!(messages(&entries), expected);
+        assert_eq!(journal.last_seq(), 3);
+        assert_eq!(journal.append(vec![log("d")]).unwrap(), 4);
+    }
+
+    #[test]
+    fn torn_tail_is_cut_off() {
+        let dir = temp_dir("torn");
+
+        let (mut journal, _) = Journal::open(&dir, false).unwrap();
+        journal.append(vec![log("a"), log("b")]).unwrap();
+        let path = journal.path.clone();
+        drop(journal);
+
+        // Host died in the middle of writing third entry
+        let valid_len = std::fs::metadata(&path).unwrap().len();
+        let torn = encode(&Entry {
+            seq: 3,
+            time: Timestamp::now(),
+            event: log("c").1,
+        })
+        .unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&torn[..torn.len() / 2]).unwrap();
+        drop(file);
+
+        let (mut journal, entries) = Journal::open(&dir, false).unwrap();
+        assert_eq!(
+            messages(&entries),
+            vec![(1, "a".to_owned()), (2, "b".to_owned())]
+        );
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), valid_len);
+
+        assert_eq!(journal.append(vec![log("c")]).unwrap(), 3);
+        drop(journal);
+
+        let (_, entries) = Journal::open(&dir, false).unwrap();
+        assert_eq!(entries.len(), 3);
+    }
+
+    #[test]
+    fn entries_after_bad_checksum_are_ignored() {
+        let dir = temp_dir("crc");
+
+        let (mut journal, _) = Journal::open(&dir, false).unwrap();
+        journal.append(vec![log("a"), log("b"), log("c")]).unwrap();
+        let path = journal.path.clone();
+        drop(journal);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, content.replacen("\"b\"", "\"x\"", 1)).unwrap();
+
+        let (_, entries) = Journal::open(&dir, false).unwrap();
+        assert_eq!(messages(&entries), vec![(1, "a".to_owned())]);
+    }
+
+    #[test]
+    fn rotated_segments_are_compacted() {
+        let dir = temp_dir("rotate");
+
+        let (mut journal, _) = Journal::open(&dir, false).unwrap();
+        journal.append(vec![log("a"), log("b")]).unwrap();
+        assert_eq!(journal.rotate().unwrap(), 2);
+        // Empty segment isn't rotated again
+        assert_eq!(journal.rotate().unwrap(), 2);
+        journal.append(vec![log("c")]).unwrap();
+
+        assert_eq!(
+            segment_names(&dir),
+            vec![
+                "journal.00000000000000000001.log",
+                "journal.00000000000000000003.log"
+            ]
+        );
+
+        // Entry 2 is not in any snapshot yet
+        compact(&dir, 1).unwrap();
+        assert_eq!(segment_names(&dir).len(), 2);
+
+        compact(&dir, 2).unwrap();
+        assert_eq!(
+            segment_names(&dir),
+            vec!["journal.00000000000000000003.log"]
+        );
+        drop(journal);
+
+        let (journal, entries) = Journal::open(&dir, false).unwrap();
+        assert_eq!(messages(&entries), vec![(3, "c".to_owned())]);
+        assert_eq!(journal.last_seq(), 3);
+    }
+
+    #[test]
+    fn legacy_journal_is_read_first() {
+        let dir = temp_dir("legacy");
+
+        let mut legacy = Vec::new();
+        for (seq, message) in [(1, "a"), (2, "b")] {
+            let (time, event) = log(message);
+            legacy.extend(encode(&Entry { seq, time, event }).unwrap());
+        }
+        std::fs::write(dir.join(LEGACY_FILE), legacy).unwrap();
+
+        let (mut journal, entries) = Journal::open(&dir, false).unwrap();
+        assert_eq!(
+            messages(&entries),
+            vec![(1, "a".to_owned()), (2, "b".to_owned())]
+        );
+        assert_eq!(journal.append(vec![log("c")]).unwrap(), 3);
+    }
+}
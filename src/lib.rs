@@ -1,8 +1,31 @@
+// Route tree is one long `or` chain, its future type nests deeper than the default limit
+#![recursion_limit = "256"]
 
+
+pub mod alerts;
+pub mod api_calls;
+pub mod archive;
+pub mod audit;
+pub mod auth;
 pub mod cli;
+pub mod compression;
 pub mod controllers;
+pub mod errors;
+pub mod events;
 pub mod helpers;
+pub mod history;
+pub mod issues;
+pub mod journal;
+pub mod liveness;
+pub mod log_buffer;
+pub mod metrics;
 pub mod models;
+pub mod persistence;
+pub mod query;
+pub mod retention;
 pub mod routes;
+pub mod runs;
+pub mod severity;
 pub mod utils;
 pub mod system_service;
+pub mod timestamp;
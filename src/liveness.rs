@@ -0,0 +1,104 @@
+// Whether a bot (or one of its keywords) is still working.
+// Bots post to `/{account}/heartbeat` periodically. Liveness is computed when asked from the
+// latest heartbeat or update, whichever is newer:
+// - alive: seen within `stale_after_secs`
+// - stale: seen within `dead_after_secs`
+// - dead: not seen for longer than that
+// `running` reported by a bot only means something while it is alive.
+use crate::models::{KeywordId, KeywordStat, MainStats};
+use crate::timestamp::Timestamp;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_STALE_AFTER_SECS: u64 = 2 * 60;
+const DEFAULT_DEAD_AFTER_SECS: u64 = 10 * 60;
+
+static CONFIG: OnceCell<LivenessConfig> = OnceCell::new();
+static DEFAULT_CONFIG: Lazy<LivenessConfig> = Lazy::new(LivenessConfig::default);
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct LivenessConfig {
+    pub stale_after_secs: Option<u64>,
+    pub dead_after_secs: Option<u64>,
+}
+
+pub fn init(config: LivenessConfig) {
+    let _ = CONFIG.set(config);
+}
+
+fn config() -> &'static LivenessConfig {
+    CONFIG.get().unwrap_or(&DEFAULT_CONFIG)
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Liveness {
+    Alive,
+    Stale,
+    Dead,
+}
+
+// Body of heartbeat, may be empty
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct Heartbeat {
+    // Defaults to true, a bot sending heartbeats is running
+    pub running: Option<bool>,
+
+    // Keywords bot is working on right now
+    #[serde(default)]
+    pub keywords: Vec<KeywordId>,
+}
+
+fn last_seen(heartbeat: Option<Timestamp>, updated: Timestamp) -> Timestamp {
+    heartbeat.map_or(updated, |h| h.max(updated))
+}
+
+pub fn of(last_seen: Timestamp, now: Timestamp) -> Liveness {
+    let config = config();
+    let stale_after = config.stale_after_secs.unwrap_or(DEFAULT_STALE_AFTER_SECS) as i64;
+    let dead_after = config.dead_after_secs.unwrap_or(DEFAULT_DEAD_AFTER_SECS) as i64;
+
+    let silent = now.secs_since(last_seen);
+    if silent > dead_after {
+        Liveness::Dead
+    } else if silent > stale_after {
+        Liveness::Stale
+    } else {
+        Liveness::Alive
+    }
+}
+
+pub fn main_last_seen(main_stats: &MainStats) -> Timestamp {
+    last_seen(main_stats.last_heartbeat_at, main_stats.last_updated_at)
+}
+
+pub fn keyword_last_seen(stat: &KeywordStat) -> Timestamp {
+    last_seen(stat.last_heartbeat_at, stat.last_updated_at)
+}
+
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct Status {
+    pub liveness: Liveness,
+    pub last_seen_at: Timestamp,
+    // What bot said, unless it stopped talking
+    pub running: bool,
+}
+
+impl Status {
+    fn new(last_seen_at: Timestamp, running: bool, now: Timestamp) -> Status {
+        let liveness = of(last_seen_at, now);
+        Status {
+            liveness,
+            last_seen_at,
+            running: running && liveness == Liveness::Alive,
+        }
+    }
+}
+
+pub fn main_status(main_stats: &MainStats, now: Timestamp) -> Status {
+    Status::new(main_last_seen(main_stats), main_stats.running, now)
+}
+
+pub fn keyword_status(stat: &KeywordStat, now: Timestamp) -> Status {
+    Status::new(keyword_last_seen(stat), stat.running.unwrap_or(false), now)
+}
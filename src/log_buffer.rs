@@ -0,0 +1,145 @@
+// Bounded storage of logs.
+// Every log list (main logs of an account and logs of each keyword) is a ring buffer. Once it is
+// full the oldest log is dropped to make room and counted in `dropped_logs` of its stats. Sequence
+// numbers of logs keep increasing so a gap tells clients logs were dropped in between.
+//
+// Capacities come from `[log_buffers]` in config.toml, per account under `accounts.<name>`.
+// They are stored on the account and changed with `Event::Resize` so replay drops the same logs
+// no matter how config changes later.
+use crate::models::Log;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use std::collections::{vec_deque, HashMap, VecDeque};
+
+const DEFAULT_MAIN_CAPACITY: usize = 10_000;
+const DEFAULT_KEYWORD_CAPACITY: usize = 1_000;
+
+static CONFIG: OnceCell<LogBuffersConfig> = OnceCell::new();
+static DEFAULT_CONFIG: Lazy<LogBuffersConfig> = Lazy::new(LogBuffersConfig::default);
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct Capacities {
+    // Main logs kept per account
+    pub main_capacity: Option<usize>,
+    // Logs kept per keyword
+    pub keyword_capacity: Option<usize>,
+}
+
+impl Capacities {
+    fn or(&self, other: &Capacities) -> Capacities {
+        Capacities {
+            main_capacity: self.main_capacity.or(other.main_capacity),
+            keyword_capacity: self.keyword_capacity.or(other.keyword_capacity),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct LogBuffersConfig {
+    #[serde(flatten)]
+    pub capacities: Capacities,
+
+    #[serde(default)]
+    pub accounts: HashMap<String, Capacities>,
+}
+
+pub fn init(config: LogBuffersConfig) {
+    let _ = CONFIG.set(config);
+}
+
+fn config() -> &'static LogBuffersConfig {
+    CONFIG.get().unwrap_or(&DEFAULT_CONFIG)
+}
+
+// Capacities an account's buffers have
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct Capacity {
+    pub main: usize,
+    pub keyword: usize,
+}
+
+impl Capacity {
+    // Accounts loaded from snapshots made before buffers were bounded
+    pub const UNBOUNDED: Capacity = Capacity {
+        main: usize::MAX,
+        keyword: usize::MAX,
+    };
+}
+
+// What config says capacities of account should be
+pub fn capacity_for(account: &str) -> Capacity {
+    let config = config();
+    let capacities = match config.accounts.get(account) {
+        Some(capacities) => capacities.or(&config.capacities),
+        None => config.capacities.clone(),
+    };
+
+    Capacity {
+        main: capacities.main_capacity.unwrap_or(DEFAULT_MAIN_CAPACITY),
+        keyword: capacities.keyword_capacity.unwrap_or(DEFAULT_KEYWORD_CAPACITY),
+    }
+}
+
+// Logs oldest first. Serialized as a plain list so snapshots with `Vec<Log>` still load.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(transparent)]
+pub struct LogBuffer(VecDeque<Log>);
+
+impl LogBuffer {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> vec_deque::Iter<'_, Log> {
+        self.0.iter()
+    }
+
+    // Adds log as newest. Returns oldest log if it had to go to stay within capacity.
+    pub fn push(&mut self, log: Log, capacity: usize) -> Option<Log> {
+        self.0.push_back(log);
+
+        if self.0.len() > capacity {
+            self.0.pop_front()
+        } else {
+            None
+        }
+    }
+
+    // Removes oldest logs until at most `keep` are left
+    pub fn drain_oldest(&mut self, keep: usize) -> Vec<Log> {
+        let count = self.0.len().saturating_sub(keep);
+        self.0.drain(..count).collect()
+    }
+
+    // `indices` must be ascending
+    pub fn remove_indices(&mut self, indices: &[usize]) -> Vec<Log> {
+        let mut removed = Vec::with_capacity(indices.len());
+        let mut kept = VecDeque::with_capacity(self.0.len().saturating_sub(indices.len()));
+        let mut indices = indices.iter().peekable();
+
+        for (i, log) in self.0.drain(..).enumerate() {
+            if indices.peek() == Some(&&i) {
+                indices.next();
+                removed.push(log);
+            } else {
+                kept.push_back(log);
+            }
+        }
+
+        self.0 = kept;
+        removed
+    }
+}
+
+impl<'a> IntoIterator for &'a LogBuffer {
+    type Item = &'a Log;
+    type IntoIter = vec_deque::Iter<'a, Log>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
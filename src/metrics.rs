@@ -0,0 +1,508 @@
+// Prometheus metrics served at `/metrics` in text exposition format.
+// Account and keyword metrics are read from database on every scrape. Server internals
+// (request latency per route and time spent waiting for database lock) are recorded as they happen.
+use crate::api_calls;
+use crate::liveness;
+use crate::models::{AccountDb, Statistics};
+use crate::severity::SeverityCounts;
+use crate::timestamp::Timestamp;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{RwLockReadGuard, RwLockWriteGuard};
+
+const PREFIX: &str = "shopee";
+
+// Seconds
+const BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+// Routes of `routes::all`. Anything else is reported as `other` so random paths don't create series.
+const ROUTES: [&str; 24] = [
+    "list_accounts",
+    "metrics",
+    "{account}/clear_log",
+    "{account}/clear_log_full",
+    "{account}/stats",
+    "{account}/stats/ws",
+    "{account}/heartbeat",
+    "{account}/runs",
+    "{account}/runs/start",
+    "{account}/runs/end",
+    "{account}/stats/add_logs",
+    "{account}/stats/set_keywords",
+    "{account}/stats/{id}/add_log",
+    "{account}/update-keyword-stats",
+    "{account}/keywords/{id}/logs",
+    "{account}/keywords/{id}/history",
+    "{account}/api_calls",
+    "{account}/issues",
+    "{account}/issues/{name}",
+    "{account}/logs",
+    "{account}/logs/batch",
+    "{account}/logs/tail",
+    "{account}/archives",
+    "{account}/archives/{name}",
+];
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: [0; BUCKETS.len()],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if secs <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+#[derive(Default)]
+struct Server {
+    // By (method, route)
+    request_duration: BTreeMap<(String, String), Histogram>,
+    // By (method, route, status)
+    requests: BTreeMap<(String, String, u16), u64>,
+    // By lock mode, read or write
+    lock_wait: BTreeMap<&'static str, Histogram>,
+}
+
+static SERVER: Lazy<Mutex<Server>> = Lazy::new(|| Mutex::new(Server::default()));
+
+// `/shop1/stats/12/add_log` becomes `{account}/stats/{id}/add_log`
+fn route_label(path: &str) -> &'static str {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    let template = match segments.as_slice() {
+        [single] => (*single).to_owned(),
+        [_, rest @ ..] => {
+            let mut template = vec!["{account}".to_owned()];
+            for (i, segment) in rest.iter().enumerate() {
+                let label = if i > 0 && (rest[i - 1] == "archives" || rest[i - 1] == "issues") {
+                    "{name}".to_owned()
+                } else if segment.parse::<u64>().is_ok() {
+                    "{id}".to_owned()
+                } else {
+                    (*segment).to_owned()
+                };
+                template.push(label);
+            }
+            template.join("/")
+        }
+        [] => String::new(),
+    };
+
+    ROUTES
+        .iter()
+        .find(|r| **r == template)
+        .copied()
+        .unwrap_or("other")
+}
+
+// Used with `warp::log::custom` on api routes
+pub fn observe_request(info: warp::log::Info) {
+    let method = info.method().as_str().to_owned();
+    let route = route_label(info.path()).to_owned();
+    let status = info.status().as_u16();
+
+    let mut server = SERVER.lock().unwrap();
+    server
+        .request_duration
+        .entry((method.clone(), route.clone()))
+        .or_default()
+        .observe(info.elapsed().as_secs_f64());
+    *server.requests.entry((method, route, status)).or_default() += 1;
+}
+
+fn observe_lock_wait(mode: &'static str, waited: Duration) {
+    let mut server = SERVER.lock().unwrap();
+    server
+        .lock_wait
+        .entry(mode)
+        .or_default()
+        .observe(waited.as_secs_f64());
+}
+
+// Same as `slot.read().await` but records how long we waited for the lock
+pub async fn read_account(slot: &AccountDb) -> RwLockReadGuard<'_, Statistics> {
+    let start = Instant::now();
+    let lock = slot.read().await;
+    observe_lock_wait("read", start.elapsed());
+    lock
+}
+
+// Same as `slot.write().await` but records how long we waited for the lock
+pub async fn write_account(slot: &AccountDb) -> RwLockWriteGuard<'_, Statistics> {
+    let start = Instant::now();
+    let lock = slot.write().await;
+    observe_lock_wait("write", start.elapsed());
+    lock
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn bool_value(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+// Collects samples of one metric family so HELP and TYPE are written once
+struct Family {
+    name: String,
+    kind: &'static str,
+    help: &'static str,
+    samples: Vec<(String, f64)>,
+}
+
+impl Family {
+    fn new(name: &str, kind: &'static str, help: &'static str) -> Self {
+        Family {
+            name: format!("{}_{}", PREFIX, name),
+            kind,
+            help,
+            samples: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, labels: String, value: f64) {
+        self.samples.push((labels, value));
+    }
+
+    fn write(&self, out: &mut String) {
+        if self.samples.is_empty() {
+            return;
+        }
+
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} {}", self.name, self.kind);
+        for (labels, value) in self.samples.iter() {
+            let _ = writeln!(out, "{}{{{}}} {}", self.name, labels, value);
+        }
+    }
+}
+
+fn write_histogram<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    series: impl Iterator<Item = (String, &'a Histogram)>,
+) {
+    let name = format!("{}_{}", PREFIX, name);
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+
+    for (labels, histogram) in series {
+        for (bound, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, histogram.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+    }
+}
+
+fn add_severity_counts(family: &mut Family, labels: &str, counts: &SeverityCounts) {
+    let counts = [
+        ("trace", counts.trace),
+        ("debug", counts.debug),
+        ("info", counts.info),
+        ("warn", counts.warn),
+        ("error", counts.error),
+        ("fatal", counts.fatal),
+        ("unknown", counts.unknown),
+    ];
+
+    for (severity, count) in counts.iter() {
+        family.add(format!("{},severity=\"{}\"", labels, severity), *count as f64);
+    }
+}
+
+// Account and keyword samples of one scrape. Accounts are added one at a time so caller only
+// needs to hold one account's lock at once.
+pub struct AccountMetrics {
+    now: Timestamp,
+    errors: Family,
+    logs: Family,
+    severities: Family,
+    bot_api_calls: Family,
+    internal_api_calls: Family,
+    log_bytes: Family,
+    dropped_logs: Family,
+    running: Family,
+    last_updated: Family,
+    last_seen: Family,
+    keywords: Family,
+    quota_ratio: Family,
+    kw_errors: Family,
+    kw_logs: Family,
+    kw_price: Family,
+    kw_position: Family,
+    kw_placement: Family,
+    kw_running: Family,
+    kw_ads_running: Family,
+    kw_max_price: Family,
+    kw_min_price: Family,
+    kw_max_expense: Family,
+}
+
+impl Default for AccountMetrics {
+    fn default() -> Self {
+        AccountMetrics::new()
+    }
+}
+
+impl AccountMetrics {
+    pub fn new() -> Self {
+        AccountMetrics {
+            now: Timestamp::now(),
+            errors: Family::new(
+                "account_errors_total",
+                "counter",
+                "Error logs and reported errors since last clear",
+            ),
+            logs: Family::new("account_logs_total", "counter", "Logs received since last clear"),
+            severities: Family::new(
+                "account_logs_by_severity_total",
+                "counter",
+                "Logs received since last clear by severity",
+            ),
+            bot_api_calls: Family::new(
+                "account_api_calls_total",
+                "counter",
+                "Api calls made by bot",
+            ),
+            internal_api_calls: Family::new(
+                "account_internal_api_calls_total",
+                "counter",
+                "Api calls made for this bot",
+            ),
+            log_bytes: Family::new(
+                "account_log_bytes",
+                "gauge",
+                "Approximate bytes used by logs in memory",
+            ),
+            dropped_logs: Family::new(
+                "account_dropped_logs_total",
+                "counter",
+                "Logs dropped because their buffer was full",
+            ),
+            running: Family::new("account_running", "gauge", "Whether bot reports itself running"),
+            last_updated: Family::new(
+                "account_last_updated_timestamp_seconds",
+                "gauge",
+                "When account was last updated",
+            ),
+            last_seen: Family::new(
+                "account_last_seen_timestamp_seconds",
+                "gauge",
+                "Latest heartbeat or update of account",
+            ),
+            keywords: Family::new("account_keywords", "gauge", "Registered keywords"),
+            quota_ratio: Family::new(
+                "account_api_quota_ratio",
+                "gauge",
+                "Fraction of api call quota used in its period",
+            ),
+
+            kw_errors: Family::new(
+                "keyword_errors_total",
+                "counter",
+                "Error logs of keyword since last clear",
+            ),
+            kw_logs: Family::new(
+                "keyword_logs_total",
+                "counter",
+                "Logs of keyword since last clear",
+            ),
+            kw_price: Family::new(
+                "keyword_current_price",
+                "gauge",
+                "Current bid price of keyword",
+            ),
+            kw_position: Family::new("keyword_ads_position", "gauge", "Ad position of keyword"),
+            kw_placement: Family::new("keyword_placement", "gauge", "Placement of keyword"),
+            kw_running: Family::new("keyword_running", "gauge", "Whether keyword is running"),
+            kw_ads_running: Family::new(
+                "keyword_ads_running",
+                "gauge",
+                "Whether ads of keyword are running",
+            ),
+            kw_max_price: Family::new(
+                "keyword_max_price_reached",
+                "gauge",
+                "Whether keyword reached its max price",
+            ),
+            kw_min_price: Family::new(
+                "keyword_min_price_reached",
+                "gauge",
+                "Whether keyword reached its min price",
+            ),
+            kw_max_expense: Family::new(
+                "keyword_max_expense_reached",
+                "gauge",
+                "Whether keyword reached its max expense",
+            ),
+        }
+    }
+
+    pub fn add(&mut self, statistics: &Statistics) {
+        let name = &statistics.main_stats.account_name;
+        let ms = &statistics.main_stats;
+        let labels = format!("account=\"{}\"", escape(name));
+
+        self.errors.add(labels.clone(), ms.error_counts as f64);
+        self.logs.add(labels.clone(), ms.log_counts as f64);
+        add_severity_counts(&mut self.severities, &labels, &ms.severity_counts);
+        self.bot_api_calls.add(labels.clone(), ms.no_api_calls as f64);
+        self.internal_api_calls.add(labels.clone(), ms.no_internal_api_calls as f64);
+        self.log_bytes.add(labels.clone(), ms.log_bytes as f64);
+        self.dropped_logs.add(labels.clone(), ms.dropped_logs as f64);
+        self.running.add(labels.clone(), bool_value(ms.running));
+        self.last_updated.add(labels.clone(), ms.last_updated_at.epoch_millis() as f64 / 1000.0);
+        self.last_seen.add(
+            labels.clone(),
+            liveness::main_last_seen(ms).epoch_millis() as f64 / 1000.0,
+        );
+        self.keywords.add(labels.clone(), statistics.keyword_stats.len() as f64);
+
+        if let Some(usage) = api_calls::quota_usage(name, &statistics.api_calls, self.now) {
+            self.quota_ratio.add(labels.clone(), usage.ratio);
+        }
+
+        let mut ids: Vec<_> = statistics.keyword_stats.keys().copied().collect();
+        ids.sort_unstable();
+
+        for id in ids {
+            let ks = &statistics.keyword_stats[&id].stats;
+            let labels = format!("{},keyword_id=\"{}\"", labels, id);
+
+            self.kw_errors.add(labels.clone(), ks.error_counts as f64);
+            self.kw_logs.add(labels.clone(), ks.log_counts as f64);
+
+            // Values bot never sent are left out instead of reported as 0
+            let mut gauges = [
+                (&mut self.kw_price, ks.current_price),
+                (&mut self.kw_position, ks.ads_position.map(|v| v as f64)),
+                (&mut self.kw_placement, ks.placement.map(|v| v as f64)),
+                (&mut self.kw_running, ks.running.map(bool_value)),
+                (&mut self.kw_ads_running, ks.ads_running.map(bool_value)),
+                (&mut self.kw_max_price, ks.is_max_price_reached.map(bool_value)),
+                (&mut self.kw_min_price, ks.is_min_price_reached.map(bool_value)),
+                (&mut self.kw_max_expense, ks.max_expense_reached.map(bool_value)),
+            ];
+
+            for (family, value) in gauges.iter_mut() {
+                if let Some(value) = value {
+                    family.add(labels.clone(), *value);
+                }
+            }
+        }
+    }
+
+    // Account samples followed by server internals
+    pub fn render(self) -> String {
+        let mut out = String::new();
+
+        for family in [
+            &self.errors,
+            &self.logs,
+            &self.severities,
+            &self.bot_api_calls,
+            &self.internal_api_calls,
+            &self.log_bytes,
+            &self.dropped_logs,
+            &self.running,
+            &self.last_updated,
+            &self.last_seen,
+            &self.keywords,
+            &self.quota_ratio,
+            &self.kw_errors,
+            &self.kw_logs,
+            &self.kw_price,
+            &self.kw_position,
+            &self.kw_placement,
+            &self.kw_running,
+            &self.kw_ads_running,
+            &self.kw_max_price,
+            &self.kw_min_price,
+            &self.kw_max_expense,
+        ]
+        .iter()
+        {
+            family.write(&mut out);
+        }
+
+        write_server(&mut out);
+        out
+    }
+}
+
+fn write_server(out: &mut String) {
+    let server = SERVER.lock().unwrap();
+
+    let mut requests = Family::new(
+        "http_requests_total",
+        "counter",
+        "Api requests by route and status",
+    );
+    for ((method, route, status), count) in server.requests.iter() {
+        requests.add(
+            format!("method=\"{}\",route=\"{}\",status=\"{}\"", method, route, status),
+            *count as f64,
+        );
+    }
+    requests.write(out);
+
+    if !server.request_duration.is_empty() {
+        write_histogram(
+            out,
+            "http_request_duration_seconds",
+            "Time taken to answer api requests",
+            server
+                .request_duration
+                .iter()
+                .map(|((method, route), h)| (format!("method=\"{}\",route=\"{}\"", method, route), h)),
+        );
+    }
+
+    if !server.lock_wait.is_empty() {
+        write_histogram(
+            out,
+            "db_lock_wait_seconds",
+            "Time spent waiting for database lock",
+            server
+                .lock_wait
+                .iter()
+                .map(|(mode, h)| (format!("mode=\"{}\"", mode), h)),
+        );
+    }
+}
+
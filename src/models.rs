@@ -1,42 +1,203 @@
 /// How our data look?
 //  Main logs contains when bot started to run, what is total log amount
 // Keywords logs contains indivitual keyword with their own logs
+use crate::api_calls::ApiCallSeries;
+use crate::history::History;
+use crate::issues::Issues;
+use crate::liveness::Heartbeat;
+use crate::log_buffer::{Capacity, LogBuffer};
+use crate::runs::{EndRun, Runs, StartRun};
+use crate::severity::{Severity, SeverityCounts};
+use crate::timestamp::Timestamp;
+use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::io;
 use std::sync::Arc;
 
 use tokio::sync::RwLock;
 
-type Account = String;
-type KeywordId = u64;
+pub type Account = String;
+pub type KeywordId = u64;
 type KeywordStats = HashMap<KeywordId, KeywordStatistics>;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Statistics {
     pub main_stats: MainStats,
     pub keyword_stats: KeywordStats,
+
+    // Keyword logs waiting for their keyword to be registered, oldest first.
+    // Counted in `MainStats::log_bytes`.
+    #[serde(default)]
+    pub orphan_logs: VecDeque<OrphanLog>,
+
+    // Api calls per minute, see `api_calls`
+    #[serde(default)]
+    pub api_calls: ApiCallSeries,
+
+    // Current and past runs of bot
+    #[serde(default)]
+    pub runs: Runs,
+
+    // Error logs grouped by fingerprint
+    #[serde(default)]
+    pub issues: Issues,
+
+    // Capacities of log buffers, see `log_buffer`. None until first `Event::Resize`
+    #[serde(default)]
+    pub capacity: Option<Capacity>,
+
+    // Removed logs not yet written to archive, oldest first. Stays in snapshot while archive
+    // can't be written so nothing is lost.
+    #[serde(default)]
+    pub archive_queue: VecDeque<Drained>,
+
+    // Logs pushed out of full buffers. They go to archive queue together once there are enough
+    // of them or on next retention sweep, so a busy account doesn't write a file per log.
+    #[serde(default)]
+    pub overflow: Drained,
+
+    // Logs pushed out while current event is applied, see `apply_event`
+    #[serde(skip)]
+    evicted: Drained,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct KeywordStatistics {
     pub stats: KeywordStat,
-    pub keyword_logs: Vec<Log>,
+    pub keyword_logs: LogBuffer,
+
+    // Changes of price, ad position and placement over time
+    #[serde(default)]
+    pub history: History,
 }
 
 impl Statistics {
-    pub fn new(account: Account) -> Self {
+    pub fn new(account: Account, time: Timestamp) -> Self {
         Statistics {
-            main_stats: MainStats::new(account),
+            main_stats: MainStats::new(account, time),
             keyword_stats: HashMap::new(),
+            orphan_logs: VecDeque::new(),
+            api_calls: ApiCallSeries::default(),
+            runs: Runs::default(),
+            issues: Issues::default(),
+            capacity: None,
+            archive_queue: VecDeque::new(),
+            overflow: Drained::default(),
+            evicted: Drained::default(),
+        }
+    }
+
+    // Moves overflow to archive queue as one batch
+    pub fn queue_overflow(&mut self) {
+        if !self.overflow.is_empty() {
+            let overflow = std::mem::take(&mut self.overflow);
+            self.archive_queue.push_back(overflow);
+        }
+    }
+
+    pub fn capacity(&self) -> Capacity {
+        self.capacity.unwrap_or(Capacity::UNBOUNDED)
+    }
+
+    // Recomputes byte counters from logs. Snapshots made before bytes were tracked have none.
+    pub fn recount_bytes(&mut self) {
+        let mut total = bytes_of(&self.main_stats.logs);
+        total += bytes_of(self.orphan_logs.iter().map(|orphan| &orphan.log));
+
+        for ks in self.keyword_stats.values_mut() {
+            ks.stats.log_bytes = bytes_of(&ks.keyword_logs);
+            total += ks.stats.log_bytes;
+        }
+
+        self.main_stats.log_bytes = total;
+    }
+
+    // Takes bytes of removed logs off the counters
+    fn forget_bytes(&mut self, drained: &Drained) {
+        let mut total = bytes_of(&drained.main_logs);
+
+        for (id, logs) in drained.keyword_logs.iter() {
+            let bytes = bytes_of(logs);
+            if let Some(ks) = self.keyword_stats.get_mut(id) {
+                ks.stats.log_bytes = ks.stats.log_bytes.saturating_sub(bytes);
+            }
+            total += bytes;
         }
+
+        self.main_stats.log_bytes = self.main_stats.log_bytes.saturating_sub(total);
     }
 }
 
-pub type Db = Arc<RwLock<HashMap<Account, Statistics>>>;
+pub fn bytes_of<'a>(logs: impl IntoIterator<Item = &'a Log>) -> u64 {
+    logs.into_iter().map(Log::approx_bytes).sum()
+}
+
+pub type Accounts = HashMap<Account, Statistics>;
+
+// One account, locked on its own
+pub type AccountDb = Arc<RwLock<Statistics>>;
+
+// Every account has its own lock so a busy bot doesn't hold up the others.
+// The map itself is behind a std lock which is only held to look up or add an account, never
+// across an await. Keywords share the lock of their account as keyword updates change account
+// wide counters too.
+// Whoever needs more than one account locks them one at a time so locks are never nested.
+#[derive(Default)]
+pub struct Store {
+    accounts: std::sync::RwLock<HashMap<Account, AccountDb>>,
+}
+
+pub type Db = Arc<Store>;
+
+impl Store {
+    pub fn new(accounts: Accounts) -> Store {
+        Store {
+            accounts: std::sync::RwLock::new(
+                accounts
+                    .into_iter()
+                    .map(|(account, statistics)| (account, Arc::new(RwLock::new(statistics))))
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn get(&self, account: &str) -> Option<AccountDb> {
+        self.accounts.read().unwrap().get(account).cloned()
+    }
+
+    // For events which create their account, see `Event::creates_account`
+    pub fn get_or_create(&self, account: &str) -> AccountDb {
+        if let Some(slot) = self.get(account) {
+            return slot;
+        }
+
+        self.accounts
+            .write()
+            .unwrap()
+            .entry(account.to_owned())
+            .or_insert_with(|| {
+                Arc::new(RwLock::new(Statistics::new(
+                    account.to_owned(),
+                    Timestamp::now(),
+                )))
+            })
+            .clone()
+    }
+
+    pub fn all(&self) -> Vec<(Account, AccountDb)> {
+        self.accounts
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(account, slot)| (account.clone(), slot.clone()))
+            .collect()
+    }
+}
 
 pub fn blank_db() -> Db {
-    Arc::new(RwLock::new(HashMap::new()))
+    Arc::new(Store::default())
 }
 
 // Stats is top level statistics
@@ -53,7 +214,11 @@ pub struct MainStats {
     // Total number of logs . It is main log + all log from keywords
     pub log_counts: u64,
 
-    // Currently unused
+    // Number of logs per severity. It is main log + all log from keywords
+    #[serde(default)]
+    pub severity_counts: SeverityCounts,
+
+    // What bot last reported. Only trust it while bot is alive, see `liveness`
     pub running: bool,
 
     // Total api calls made by other bot .
@@ -62,29 +227,76 @@ pub struct MainStats {
     // API calls used for this bot
     pub no_internal_api_calls: u64,
 
-    // When the other bot was started?
-    pub started_at: String,
+    // When the other bot was started? Moved forward whenever a new run starts, see `runs`
+    pub started_at: Timestamp,
 
     // when the bot was last updated. When new logs , keywoord logs come this field must be updated
-    pub last_updated_at: String,
+    pub last_updated_at: Timestamp,
+
+    // When bot last sent a heartbeat
+    #[serde(default)]
+    pub last_heartbeat_at: Option<Timestamp>,
+
+    // Old logs are removed by retention policies, see `retention`, or dropped once buffer is full
+    pub logs: LogBuffer,
+
+    // Sequence number of last log (main or keyword) accepted for this account
+    #[serde(default)]
+    pub last_log_seq: u64,
 
-    // Logs are cleared out and only top 100 logs are placed if program memory goes beyond
-    // 1G
-    pub logs: Vec<Log>,
+    // Approximate bytes used by logs of this account. It is main log + all log from keywords
+    // + buffered orphan logs
+    #[serde(default)]
+    pub log_bytes: u64,
+
+    // Logs dropped because their buffer was full. It is main log + all log from keywords
+    #[serde(default)]
+    pub dropped_logs: u64,
+
+    // What happened to logs sent for keywords which weren't registered yet
+    #[serde(default)]
+    pub orphans: OrphanCounts,
 }
 
 impl MainStats {
-    pub fn new(account_name: Account) -> Self {
+    // Copy with an empty log buffer, for when only the counters are needed
+    pub fn without_logs(&self) -> MainStats {
+        MainStats {
+            account_name: self.account_name.clone(),
+            error_counts: self.error_counts,
+            log_counts: self.log_counts,
+            severity_counts: self.severity_counts.clone(),
+            running: self.running,
+            no_api_calls: self.no_api_calls,
+            no_internal_api_calls: self.no_internal_api_calls,
+            started_at: self.started_at,
+            last_updated_at: self.last_updated_at,
+            last_heartbeat_at: self.last_heartbeat_at,
+            logs: LogBuffer::default(),
+            last_log_seq: self.last_log_seq,
+            log_bytes: self.log_bytes,
+            dropped_logs: self.dropped_logs,
+            orphans: self.orphans.clone(),
+        }
+    }
+
+    pub fn new(account_name: Account, time: Timestamp) -> Self {
         MainStats {
             account_name,
             error_counts: 0,
             running: false,
             no_api_calls: 0,
             log_counts: 0,
+            severity_counts: SeverityCounts::default(),
             no_internal_api_calls: 0,
-            started_at: crate::helpers::current_time_string(),
-            last_updated_at: crate::helpers::current_time_string(),
-            logs: Vec::new(),
+            started_at: time,
+            last_updated_at: time,
+            last_heartbeat_at: None,
+            logs: LogBuffer::default(),
+            last_log_seq: 0,
+            log_bytes: 0,
+            dropped_logs: 0,
+            orphans: OrphanCounts::default(),
         }
     }
 }
@@ -100,18 +312,131 @@ pub struct UpdateStat {
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Log {
-    pub r#type: String,
-    pub time: String,
+    // Still sent as `type` string by bot. See `Severity::parse` for accepted values.
+    pub r#type: Severity,
+
+    // Time reported by bot. None if bot didn't send it or sent something we can't parse
+    #[serde(default, deserialize_with = "crate::timestamp::deserialize_lenient")]
+    pub time: Option<Timestamp>,
+
+    // Time when server accepted the log
+    #[serde(default = "Timestamp::now")]
+    pub received_at: Timestamp,
+
     pub message: String,
     pub meta: Option<Value>,
+
+    // Assigned by server when log is accepted. Increases by one per account.
+    #[serde(default)]
+    pub seq: u64,
+}
+
+// One entry of batch ingestion. Main log if keyword_id is not given.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BatchLog {
+    pub keyword_id: Option<KeywordId>,
+    #[serde(flatten)]
+    pub log: Log,
+}
+
+// What to do with a keyword log when keyword (or account) isn't registered yet.
+// Chosen by `orphan_logs` in config and stored in events so replay does the same thing.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum OrphanPolicy {
+    // Log is discarded
+    #[default]
+    Drop,
+    // Placeholder keyword (and account) is created
+    Create,
+    // Log waits until keyword is registered and is discarded after grace_secs. At most
+    // max_logs wait per account, oldest are discarded first.
+    Buffer {
+        grace_secs: u64,
+        #[serde(default = "default_orphan_max_logs")]
+        max_logs: usize,
+    },
+}
+
+// Also used for events journaled before the buffer was bounded
+pub fn default_orphan_max_logs() -> usize {
+    1000
+}
+
+static ORPHAN_POLICY: OnceCell<OrphanPolicy> = OnceCell::new();
+
+pub fn init_orphan_policy(policy: OrphanPolicy) {
+    let _ = ORPHAN_POLICY.set(policy);
+}
+
+pub fn orphan_policy() -> OrphanPolicy {
+    ORPHAN_POLICY.get().copied().unwrap_or_default()
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OrphanLog {
+    pub keyword_id: KeywordId,
+    pub expires_at: Timestamp,
+    pub log: Log,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct OrphanCounts {
+    // Waiting in buffer right now
+    pub buffered: u64,
+    // Moved to their keyword once it was registered
+    pub adopted: u64,
+    // Grace period ran out before keyword was registered
+    pub expired: u64,
+    pub dropped: u64,
+    // Placeholder keywords created by a log
+    pub created_keywords: u64,
+}
+
+impl Log {
+    // Time used for sorting and time range queries
+    pub fn effective_time(&self) -> Timestamp {
+        self.time.unwrap_or(self.received_at)
+    }
+
+    // Rough number of bytes this log takes in memory
+    pub fn approx_bytes(&self) -> u64 {
+        let meta = self.meta.as_ref().map(value_bytes).unwrap_or(0);
+        (std::mem::size_of::<Log>() + self.r#type.as_str().len() + self.message.len() + meta) as u64
+    }
+}
+
+fn value_bytes(value: &Value) -> usize {
+    std::mem::size_of::<Value>()
+        + match value {
+            Value::String(s) => s.len(),
+            Value::Array(values) => values.iter().map(value_bytes).sum(),
+            Value::Object(map) => map.iter().map(|(k, v)| k.len() + value_bytes(v)).sum(),
+            _ => 0,
+        }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct KeywordStat {
     pub id: u64,
-    pub last_updated_at: String,
+    pub last_updated_at: Timestamp,
+
+    // When keyword was last listed in a heartbeat of its bot
+    #[serde(default)]
+    pub last_heartbeat_at: Option<Timestamp>,
+
     pub error_counts: u64,
     pub log_counts: u64,
+    #[serde(default)]
+    pub severity_counts: SeverityCounts,
+
+    // Approximate bytes used by logs of this keyword
+    #[serde(default)]
+    pub log_bytes: u64,
+
+    // Logs dropped because buffer of this keyword was full
+    #[serde(default)]
+    pub dropped_logs: u64,
 
     pub name: Option<String>,
     pub keyword: Option<String>,
@@ -143,14 +468,14 @@ pub struct UpdateKeywordStat {
 }
 
 impl KeywordStatistics {
-    pub fn update(stats: &mut Statistics, input: &UpdateKeywordStat) {
+    pub fn update(stats: &mut Statistics, input: &UpdateKeywordStat, time: Timestamp) {
         let main_stats = &mut stats.main_stats;
-        main_stats.last_updated_at = crate::helpers::current_time_string();
+        main_stats.last_updated_at = time;
 
         let keyword_stats = &mut stats.keyword_stats;
 
         if let Some(ks) = keyword_stats.get_mut(&input.id) {
-            ks.stats.last_updated_at = crate::helpers::current_time_string();
+            ks.stats.last_updated_at = time;
 
             if let Some(ru) = input.running {
                 ks.stats.running = Some(ru);
@@ -180,16 +505,22 @@ impl KeywordStatistics {
             if let Some(cp) = input.current_price {
                 ks.stats.current_price = Some(cp);
             }
+
+            ks.history.record(&ks.stats, time);
         } else {
-            let keyword_statistics = KeywordStatistics {
+            let mut keyword_statistics = KeywordStatistics {
                 stats: KeywordStat {
                     id: input.id,
                     error_counts: 0,
                     log_counts: 0,
+                    severity_counts: SeverityCounts::default(),
+                    log_bytes: 0,
+                    dropped_logs: 0,
                     name: input.name.to_owned(),
                     keyword: input.keyword.to_owned(),
                     placement: input.placement,
-                    last_updated_at: crate::helpers::current_time_string(),
+                    last_updated_at: time,
+                    last_heartbeat_at: None,
                     running: input.running,
                     ads_running: input.ads_running,
                     ads_position: input.ads_position,
@@ -198,120 +529,585 @@ impl KeywordStatistics {
                     is_min_price_reached: None,
                     max_expense_reached: None,
                 },
-                keyword_logs: Vec::with_capacity(1000),
+                keyword_logs: LogBuffer::default(),
+                history: History::default(),
             };
+            keyword_statistics
+                .history
+                .record(&keyword_statistics.stats, time);
 
             keyword_stats.insert(input.id, keyword_statistics);
+            Self::adopt_orphans(stats, input.id, time);
+        }
+    }
+
+    // Empty keyword for a log which came before its keyword was registered
+    fn create_placeholder(stats: &mut Statistics, id: KeywordId, time: Timestamp) {
+        let input = UpdateKeywordStat {
+            id,
+            name: None,
+            current_price: None,
+            keyword: None,
+            placement: None,
+            running: None,
+            error_counts: None,
+            ads_running: None,
+            ads_position: None,
+            logs: None,
+            is_max_price_reached: None,
+            is_min_price_reached: None,
+            max_expense_reached: None,
+        };
+
+        Self::update(stats, &input, time);
+        stats.main_stats.orphans.created_keywords += 1;
+    }
+
+    // Moves buffered logs of a newly registered keyword into it
+    fn adopt_orphans(stats: &mut Statistics, id: KeywordId, time: Timestamp) {
+        let (adopted, waiting): (VecDeque<OrphanLog>, VecDeque<OrphanLog>) = stats
+            .orphan_logs
+            .drain(..)
+            .partition(|orphan| orphan.keyword_id == id);
+        stats.orphan_logs = waiting;
+
+        // `insert_log` counts them again as keyword logs
+        let bytes = bytes_of(adopted.iter().map(|orphan| &orphan.log));
+        let main_stats = &mut stats.main_stats;
+        main_stats.log_bytes = main_stats.log_bytes.saturating_sub(bytes);
+
+        let orphans = &mut main_stats.orphans;
+        orphans.buffered = orphans.buffered.saturating_sub(adopted.len() as u64);
+        orphans.adopted += adopted.len() as u64;
+
+        for orphan in adopted {
+            Self::insert_log(stats, id, orphan.log, time);
         }
     }
 
-    pub fn add_logs(stats: &mut Statistics, id: KeywordId, input: Log) {
+    pub fn add_logs(stats: &mut Statistics, id: KeywordId, mut input: Log, time: Timestamp) {
+        input.received_at = time;
+        Self::insert_log(stats, id, input, time);
+    }
+
+    // Keeps received_at of log so buffered logs keep time they were really received
+    fn insert_log(stats: &mut Statistics, id: KeywordId, mut input: Log, time: Timestamp) {
+        let capacity = stats.capacity().keyword;
         let main_stats = &mut stats.main_stats;
 
         let keyword_stats = &mut stats.keyword_stats;
         if let Some(ks) = keyword_stats.get_mut(&id) {
-            main_stats.last_updated_at = crate::helpers::current_time_string();
+            main_stats.last_updated_at = time;
 
-            if input.r#type == "error" {
+            if input.r#type.is_error() {
                 main_stats.error_counts += 1;
                 ks.stats.error_counts += 1;
             }
 
+            main_stats.severity_counts.add(&input.r#type);
+            ks.stats.severity_counts.add(&input.r#type);
+
             main_stats.log_counts += 1;
             ks.stats.log_counts += 1;
 
-            ks.keyword_logs.push(input);
+            main_stats.last_log_seq += 1;
+            input.seq = main_stats.last_log_seq;
+            crate::events::publish_log(&main_stats.account_name, Some(id), &input);
+
+            let bytes = input.approx_bytes();
+            main_stats.log_bytes += bytes;
+            ks.stats.log_bytes += bytes;
+
+            stats.runs.count_log(&input.r#type);
+            stats.issues.record(&input, Some(id));
+
+            if let Some(dropped) = ks.keyword_logs.push(input, capacity) {
+                let bytes = dropped.approx_bytes();
+                main_stats.log_bytes = main_stats.log_bytes.saturating_sub(bytes);
+                ks.stats.log_bytes = ks.stats.log_bytes.saturating_sub(bytes);
+                main_stats.dropped_logs += 1;
+                ks.stats.dropped_logs += 1;
+                stats.evicted.push_keyword_log(id, dropped);
+            }
         }
     }
 }
 
+// Every change to database is described by an event.
+// Events are written to journal before they are applied so replaying them
+// on top of last snapshot gives back exact same state.
 #[derive(Debug, Deserialize, Serialize, Clone)]
-struct BackupStatistics {
-    stats: MainStats,
-    keyword: HashMap<KeywordId, Vec<Log>>,
+#[serde(tag = "event")]
+pub enum Event {
+    UpdateStats {
+        account: Account,
+        input: UpdateStat,
+    },
+    AddLog {
+        account: Account,
+        input: Log,
+    },
+    SetKeywords {
+        account: Account,
+        input: Vec<UpdateKeywordStat>,
+    },
+    AddKeywordLog {
+        account: Account,
+        id: KeywordId,
+        input: Log,
+        #[serde(default)]
+        orphans: OrphanPolicy,
+    },
+    // Many main and keyword logs journaled as one entry
+    AddLogBatch {
+        account: Account,
+        input: Vec<BatchLog>,
+        #[serde(default)]
+        orphans: OrphanPolicy,
+        // Logs of unknown keywords which drop policy discarded, only counted
+        #[serde(default)]
+        dropped: u64,
+    },
+    UpdateKeyword {
+        account: Account,
+        input: UpdateKeywordStat,
+    },
+    Clear {
+        account: Account,
+        count: usize,
+    },
+    Heartbeat {
+        account: Account,
+        input: Heartbeat,
+    },
+    StartRun {
+        account: Account,
+        input: StartRun,
+    },
+    EndRun {
+        account: Account,
+        input: EndRun,
+    },
+    // Capacities of log buffers changed in config, or account is new
+    Resize {
+        account: Account,
+        capacity: Capacity,
+    },
+    // Logs removed by retention policy
+    Expire {
+        account: Account,
+        lists: Vec<ExpiredLogs>,
+    },
 }
 
-// // We might want to reanalyze previous record for that we are providing ability to
-// // use old database.
-// pub async fn load_old_database() -> Option<Statistics> {
-//     let aa = std::env::var("JSON_FILE_PATH");
+// Positions of expired logs in main log list (keyword_id None) or in a keyword's list.
+// Replay reaches same state so positions stay valid no matter how policy changes later.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ExpiredLogs {
+    pub keyword_id: Option<KeywordId>,
+    pub indices: Vec<usize>,
+}
 
-//     if aa.is_ok() {
-//         let ff = std::fs::File::open(aa.unwrap()).unwrap();
+impl Event {
+    pub fn account(&self) -> &str {
+        match self {
+            Event::UpdateStats { account, .. }
+            | Event::AddLog { account, .. }
+            | Event::SetKeywords { account, .. }
+            | Event::AddKeywordLog { account, .. }
+            | Event::AddLogBatch { account, .. }
+            | Event::UpdateKeyword { account, .. }
+            | Event::Clear { account, .. }
+            | Event::Heartbeat { account, .. }
+            | Event::StartRun { account, .. }
+            | Event::EndRun { account, .. }
+            | Event::Resize { account, .. }
+            | Event::Expire { account, .. } => account,
+        }
+    }
 
-//         let json: BackupStatistics = serde_json::from_reader(ff).unwrap();
+    // Whether event makes its account when it doesn't exist yet
+    pub fn creates_account(&self) -> bool {
+        match self {
+            Event::UpdateStats { .. }
+            | Event::AddLog { .. }
+            | Event::Heartbeat { .. }
+            | Event::StartRun { .. } => true,
+            Event::AddKeywordLog { orphans, .. } => *orphans != OrphanPolicy::Drop,
+            Event::AddLogBatch { input, orphans, .. } => {
+                *orphans != OrphanPolicy::Drop || input.iter().any(|l| l.keyword_id.is_none())
+            }
+            Event::SetKeywords { .. }
+            | Event::UpdateKeyword { .. }
+            | Event::Clear { .. }
+            | Event::EndRun { .. }
+            | Event::Resize { .. }
+            | Event::Expire { .. } => false,
+        }
+    }
 
-//         let stats = json.stats;
-//         let keyword = json.keyword;
+    // Whether event adds logs, which can push older logs out of full buffers
+    pub fn inserts_logs(&self) -> bool {
+        match self {
+            Event::AddLog { .. }
+            | Event::AddKeywordLog { .. }
+            | Event::AddLogBatch { .. }
+            // Registering a keyword adopts its buffered orphans
+            | Event::SetKeywords { .. }
+            | Event::UpdateKeyword { .. } => true,
+            Event::UpdateStats { .. }
+            | Event::Clear { .. }
+            | Event::Heartbeat { .. }
+            | Event::StartRun { .. }
+            | Event::EndRun { .. }
+            | Event::Resize { .. }
+            | Event::Expire { .. } => false,
+        }
+    }
+}
+
+// Logs removed from database by `clear_db`, retention or full buffers
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct Drained {
+    pub main_logs: Vec<Log>,
+    pub keyword_logs: Vec<(KeywordId, Vec<Log>)>,
+}
+
+impl Drained {
+    pub fn push_keyword_log(&mut self, id: KeywordId, log: Log) {
+        match self.keyword_logs.iter_mut().find(|(kid, _)| *kid == id) {
+            Some((_, logs)) => logs.push(log),
+            None => self.keyword_logs.push((id, vec![log])),
+        }
+    }
+
+    pub fn append(&mut self, other: Drained) {
+        self.main_logs.extend(other.main_logs);
+        for (id, logs) in other.keyword_logs {
+            for log in logs {
+                self.push_keyword_log(id, log);
+            }
+        }
+    }
 
-//         let account = stats.account_name.to_owned();
+    pub fn is_empty(&self) -> bool {
+        self.main_logs.is_empty() && self.keyword_logs.is_empty()
+    }
 
-//         let mut stats_hm = HashMap::new();
-//         stats_hm.insert(account.clone(), stats);
+    pub fn len(&self) -> usize {
+        let keyword_logs: usize = self.keyword_logs.iter().map(|(_, logs)| logs.len()).sum();
+        self.main_logs.len() + keyword_logs
+    }
+
+    pub fn summary(&self) -> ClearSummary {
+        ClearSummary {
+            main_logs: self.main_logs.len(),
+            keyword_logs: self.keyword_logs.iter().map(|(_, logs)| logs.len()).sum(),
+            keywords: self.keyword_logs.len(),
+        }
+    }
+}
 
-//         let mut keywords_hm = HashMap::new();
-//         keywords_hm.insert(account, keyword);
+// `?dry_run=true` reports what clear would remove without removing it
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ClearQuery {
+    #[serde(default)]
+    pub dry_run: bool,
+}
 
-//         let arc_stats = Arc::new(Mutex::new(stats_hm));
-//         let arc_keywords = Arc::new(Mutex::new(keywords_hm));
+// How many logs a clear removed (or would remove)
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ClearSummary {
+    pub main_logs: usize,
+    pub keyword_logs: usize,
+    // Number of keywords which lost logs
+    pub keywords: usize,
+}
 
-//         return Some(Statistics {
-//             stats: arc_stats,
-//             keyword_stats: arc_keywords,
-//         });
-//     }
+// Records event in journal and then applies it. Returns how many logs the event removed, if any.
+// Removed logs are archived before this returns, or left in archive queue when that fails.
+// Caller must hold write lock of the account so journal order of an account is same as apply
+// order. Events of different accounts never touch each other so their order doesn't matter.
+// Nothing is applied when event can't be journaled.
+pub async fn commit(statistics: &mut Statistics, event: Event) -> io::Result<Option<ClearSummary>> {
+    let drained = commit_event(statistics, &event).await?;
+
+    // Done after the event so a new account gets its capacities right after being created.
+    // Event itself is already applied so a failed resize is only logged, next commit retries it.
+    let capacity = crate::log_buffer::capacity_for(event.account());
+    if statistics.capacity != Some(capacity) && !matches!(event, Event::Resize { .. }) {
+        let resize = Event::Resize {
+            account: event.account().to_owned(),
+            capacity,
+        };
+        if let Err(e) = commit_event(statistics, &resize).await {
+            let account = event.account();
+            tracing::error!("Unable to resize log buffers of {}: {:?}", account, e);
+        }
+    }
 
-//     None
-// }
+    Ok(drained)
+}
 
-pub async fn clear_database_periodically(db: Db) {
-    loop {
-        println!("Waiting 6 hour to clear DB!");
-        use tokio::time::Duration;
+async fn commit_event(
+    statistics: &mut Statistics,
+    event: &Event,
+) -> io::Result<Option<ClearSummary>> {
+    let time = Timestamp::now();
+    crate::journal::append(time, event).await?;
+
+    let captured = crate::events::capture(statistics, event);
+    let bytes_before = statistics.main_stats.log_bytes;
+    let errors_before = statistics.main_stats.error_counts;
+
+    let drained = apply_event(statistics, event, time);
+    let summary = drained.as_ref().map(Drained::summary);
+    match drained {
+        Some(drained) if event.inserts_logs() => {
+            statistics.overflow.append(drained);
+            if statistics.overflow.len() >= crate::archive::OVERFLOW_BATCH {
+                statistics.queue_overflow();
+                crate::archive::store(event.account(), &mut statistics.archive_queue).await;
+            }
+        }
+        Some(drained) => {
+            if !drained.is_empty() {
+                statistics.archive_queue.push_back(drained);
+            }
+            crate::archive::store(event.account(), &mut statistics.archive_queue).await;
+        }
+        None => {}
+    }
 
-        tokio::time::delay_for(Duration::from_secs(6 * 60 * 60)).await;
+    if let Some(captured) = captured {
+        crate::events::publish_changes(statistics, event, captured);
+    }
 
-        println!("Clearing Old Records!");
+    crate::alerts::evaluate(statistics, event, errors_before, time);
+    crate::retention::track_bytes(bytes_before, statistics.main_stats.log_bytes);
 
-        // As database keeeps growing we must keep memory usage in track
-        // For this  we will check how much process is using memory
-        // if its greator than zero we will clear it
-        let mut lock = db.write().await;
+    Ok(summary)
+}
+
+// Applies journal entry on boot, creating its account the same way the live event did.
+// Logs pushed out of full buffers go to overflow again, they may not have been archived before
+// the crash. Those already archived by a sweep are archived twice, which beats losing them.
+// Logs drained by clear, retention and resize were archived when event first happened.
+pub fn replay(accounts: &mut Accounts, event: &Event, time: Timestamp) {
+    let account = event.account();
+    if !accounts.contains_key(account) && event.creates_account() {
+        accounts.insert(account.to_owned(), Statistics::new(account.to_owned(), time));
+    }
+
+    if let Some(statistics) = accounts.get_mut(account) {
+        match apply_event(statistics, event, time) {
+            Some(drained) if event.inserts_logs() => statistics.overflow.append(drained),
+            _ => {}
+        }
+    }
+}
+
+// `time` is the time when event was first received so replay produces same timestamps.
+// Returns logs drained by clear, retention or full buffers so caller can archive them.
+pub fn apply_event(statistics: &mut Statistics, event: &Event, time: Timestamp) -> Option<Drained> {
+    expire_orphans(statistics, time);
+
+    match event {
+        Event::UpdateStats { input, .. } => {
+            let main_stats = &mut statistics.main_stats;
+            main_stats.last_updated_at = time;
+
+            if let Some(error_counts) = input.error_counts {
+                main_stats.error_counts += error_counts;
+                statistics.runs.count_errors(error_counts);
+            }
+
+            if let Some(running) = input.running {
+                main_stats.running = running;
+            }
+
+            if let Some(diff) = input.no_of_api_call_diff {
+                main_stats.no_api_calls += diff;
+                statistics.api_calls.record(diff, time);
+                statistics.runs.count_api_calls(diff);
+            }
+        }
+        Event::AddLog { input, .. } => add_main_log(statistics, input.clone(), time),
+        Event::AddLogBatch {
+            input,
+            orphans,
+            dropped,
+            ..
+        } => {
+            statistics.main_stats.orphans.dropped += dropped;
+
+            for item in input.iter() {
+                match item.keyword_id {
+                    None => add_main_log(statistics, item.log.clone(), time),
+                    Some(id) => add_keyword_log(statistics, id, item.log.clone(), *orphans, time),
+                }
+            }
+        }
+        Event::SetKeywords { input, .. } => {
+            statistics.main_stats.last_updated_at = time;
+
+            for ii in input.iter() {
+                KeywordStatistics::update(statistics, ii, time)
+            }
+        }
+        Event::AddKeywordLog {
+            id, input, orphans, ..
+        } => add_keyword_log(statistics, *id, input.clone(), *orphans, time),
+        Event::UpdateKeyword { input, .. } => KeywordStatistics::update(statistics, input, time),
+        Event::Clear { count, .. } => {
+            let drained = clear_db(statistics, *count, time);
+            statistics.forget_bytes(&drained);
+            return Some(drained);
+        }
+        Event::Heartbeat { input, .. } => {
+            statistics.main_stats.last_heartbeat_at = Some(time);
+            statistics.main_stats.running = input.running.unwrap_or(true);
+
+            for id in input.keywords.iter() {
+                if let Some(ks) = statistics.keyword_stats.get_mut(id) {
+                    ks.stats.last_heartbeat_at = Some(time);
+                }
+            }
+        }
+        Event::StartRun { input, .. } => {
+            statistics.runs.start(input, time);
+            statistics.main_stats.started_at = time;
+            statistics.main_stats.running = true;
+            statistics.main_stats.last_updated_at = time;
+        }
+        Event::EndRun { input, .. } => {
+            statistics.runs.end(input, time);
+            statistics.main_stats.running = false;
+            statistics.main_stats.last_updated_at = time;
+        }
+        Event::Resize { capacity, .. } => {
+            statistics.capacity = Some(*capacity);
+            let drained = shrink_buffers(statistics, *capacity);
+            statistics.forget_bytes(&drained);
+            return Some(drained);
+        }
+        Event::Expire { lists, .. } => {
+            let drained = expire_logs(statistics, lists);
+            statistics.forget_bytes(&drained);
+            return Some(drained);
+        }
+    }
 
-        let vv = lock.values_mut();
+    // Bytes of evicted logs were already taken off when they were pushed out
+    let evicted = std::mem::take(&mut statistics.evicted);
+    if evicted.is_empty() {
+        None
+    } else {
+        Some(evicted)
+    }
+}
 
-        for statistics in vv {
-            clear_db(statistics, 100).await
+fn add_keyword_log(
+    statistics: &mut Statistics,
+    id: KeywordId,
+    mut log: Log,
+    orphans: OrphanPolicy,
+    time: Timestamp,
+) {
+    if !statistics.keyword_stats.contains_key(&id) {
+        match orphans {
+            // Handlers leave these out of events and count them in `AddLogBatch::dropped`
+            OrphanPolicy::Drop => return,
+            OrphanPolicy::Create => KeywordStatistics::create_placeholder(statistics, id, time),
+            OrphanPolicy::Buffer {
+                grace_secs,
+                max_logs,
+            } => {
+                log.received_at = time;
+                statistics.main_stats.log_bytes += log.approx_bytes();
+                statistics.orphan_logs.push_back(OrphanLog {
+                    keyword_id: id,
+                    expires_at: time.add_secs(grace_secs as i64),
+                    log,
+                });
+                statistics.main_stats.orphans.buffered += 1;
+
+                while statistics.orphan_logs.len() > max_logs {
+                    if let Some(oldest) = statistics.orphan_logs.pop_front() {
+                        let main_stats = &mut statistics.main_stats;
+                        let bytes = oldest.log.approx_bytes();
+                        main_stats.log_bytes = main_stats.log_bytes.saturating_sub(bytes);
+                        main_stats.orphans.buffered = main_stats.orphans.buffered.saturating_sub(1);
+                        main_stats.orphans.dropped += 1;
+                    }
+                }
+                return;
+            }
         }
     }
+
+    KeywordStatistics::add_logs(statistics, id, log, time)
+}
+
+// Whether some buffered orphans are past their grace period. Any event drops them.
+pub fn has_expired_orphans(statistics: &Statistics, time: Timestamp) -> bool {
+    statistics
+        .orphan_logs
+        .iter()
+        .any(|orphan| orphan.expires_at <= time)
+}
+
+// Drops buffered orphans whose grace period is over
+fn expire_orphans(statistics: &mut Statistics, time: Timestamp) {
+    if !has_expired_orphans(statistics, time) {
+        return;
+    }
+
+    let (expired, waiting): (VecDeque<OrphanLog>, VecDeque<OrphanLog>) = statistics
+        .orphan_logs
+        .drain(..)
+        .partition(|orphan| orphan.expires_at <= time);
+    statistics.orphan_logs = waiting;
+
+    let bytes = bytes_of(expired.iter().map(|orphan| &orphan.log));
+    let main_stats = &mut statistics.main_stats;
+    main_stats.log_bytes = main_stats.log_bytes.saturating_sub(bytes);
+
+    let orphans = &mut main_stats.orphans;
+    orphans.buffered = orphans.buffered.saturating_sub(expired.len() as u64);
+    orphans.expired += expired.len() as u64;
 }
 
-pub async fn clear_db(statistics: &mut Statistics, count: usize) {
-    // use std::borrow::Cow;
+fn add_main_log(statistics: &mut Statistics, mut input: Log, time: Timestamp) {
+    let capacity = statistics.capacity().main;
+    let main_stats = &mut statistics.main_stats;
+    main_stats.last_updated_at = time;
+
+    if input.r#type.is_error() {
+        main_stats.error_counts += 1
+    }
+
+    main_stats.severity_counts.add(&input.r#type);
 
-    // #[derive(Debug, Deserialize, Serialize, Clone)]
-    // struct Backup<'a> {
-    //     stats: Cow<'a, Statistics>,
-    // };
+    main_stats.last_log_seq += 1;
+    input.seq = main_stats.last_log_seq;
+    input.received_at = time;
+    crate::events::publish_log(&main_stats.account_name, None, &input);
 
-    // {
-    //     let content = serde_json::to_string_pretty(&Backup {
-    //         stats: Cow::Borrowed(&*statistics),
-    //     })
-    //     .unwrap();
+    main_stats.log_bytes += input.approx_bytes();
 
-    //     let path = crate::helpers::sanitize(
-    //         &("".to_owned()
-    //             + &statistics.main_stats.account_name
-    //             + "_"
-    //             + &crate::helpers::current_time_string()),
-    //     ) + ".json";
+    statistics.runs.count_log(&input.r#type);
+    statistics.issues.record(&input, None);
 
-    //     let mut new_file = File::create(path).unwrap();
-    //     new_file.write_all(&content.into_bytes()).unwrap();
-    // }
+    if let Some(dropped) = main_stats.logs.push(input, capacity) {
+        main_stats.log_bytes = main_stats.log_bytes.saturating_sub(dropped.approx_bytes());
+        main_stats.dropped_logs += 1;
+        statistics.evicted.main_logs.push(dropped);
+    }
+}
 
-    // println!("Backup done");
+pub fn clear_db(statistics: &mut Statistics, count: usize, time: Timestamp) -> Drained {
+    let mut drained = Drained::default();
 
     let mut no_of_main_log_cleared = 0;
     {
@@ -320,42 +1116,173 @@ pub async fn clear_db(statistics: &mut Statistics, count: usize) {
 
             ms.error_counts = 0;
             ms.log_counts = 0;
+            ms.severity_counts = SeverityCounts::default();
             ms.no_api_calls = 0;
             ms.no_internal_api_calls = 0;
+            ms.dropped_logs = 0;
         }
 
-        let main_logs_len = statistics.main_stats.logs.len();
-
-        if main_logs_len > count {
-            // [1,2,3,4,5,6,7] to keep 2 elem drain 0..(7-2)
-            statistics.main_stats.logs.drain(0..(main_logs_len - count));
-            no_of_main_log_cleared += main_logs_len - count;
-        }
+        drained.main_logs = statistics.main_stats.logs.drain_oldest(count);
+        no_of_main_log_cleared += drained.main_logs.len();
     }
-    println!("Main Lang Cleared");
 
     let mut no_of_keyword_drained = 0;
     {
-        let keyword_stats_hashmap = statistics.keyword_stats.values_mut();
+        let keyword_stats_hashmap = statistics.keyword_stats.iter_mut();
 
-        for kstat in keyword_stats_hashmap {
+        for (id, kstat) in keyword_stats_hashmap {
             if count == 0 {
                 let ss = &mut kstat.stats;
                 ss.error_counts = 0;
                 ss.log_counts = 0;
-                ss.last_updated_at = crate::helpers::current_time_string();
+                ss.severity_counts = SeverityCounts::default();
+                ss.dropped_logs = 0;
+                ss.last_updated_at = time;
             }
 
-            let log_len = kstat.keyword_logs.len();
-            if log_len > count {
-                kstat.keyword_logs.drain(0..(log_len - count));
-                no_of_keyword_drained += log_len - count;
+            let logs = kstat.keyword_logs.drain_oldest(count);
+            if !logs.is_empty() {
+                no_of_keyword_drained += logs.len();
+                drained.keyword_logs.push((*id, logs));
             }
         }
     }
 
-    println!(
-        "Keyword Static Cleared \n No of log cleared {} \n No of mail log cleared {}",
-        no_of_keyword_drained, no_of_main_log_cleared
+    tracing::info!(
+        "Cleared {} main logs and {} keyword logs of {}",
+        no_of_main_log_cleared, no_of_keyword_drained, statistics.main_stats.account_name
     );
+
+    drained
+}
+
+// What `clear_db` would remove, without removing anything
+pub fn preview_clear(statistics: &Statistics, count: usize) -> ClearSummary {
+    let keyword_drains: Vec<usize> = statistics
+        .keyword_stats
+        .values()
+        .map(|ks| ks.keyword_logs.len().saturating_sub(count))
+        .filter(|drained| *drained > 0)
+        .collect();
+
+    ClearSummary {
+        main_logs: statistics.main_stats.logs.len().saturating_sub(count),
+        keyword_logs: keyword_drains.iter().sum(),
+        keywords: keyword_drains.len(),
+    }
+}
+
+fn expire_logs(statistics: &mut Statistics, lists: &[ExpiredLogs]) -> Drained {
+    let mut drained = Drained::default();
+
+    for list in lists {
+        match list.keyword_id {
+            None => {
+                drained.main_logs = statistics.main_stats.logs.remove_indices(&list.indices)
+            }
+            Some(id) => {
+                if let Some(ks) = statistics.keyword_stats.get_mut(&id) {
+                    let logs = ks.keyword_logs.remove_indices(&list.indices);
+                    drained.keyword_logs.push((id, logs));
+                }
+            }
+        }
+    }
+
+    drained
+}
+
+// Drops oldest logs of every buffer which is over its new capacity
+fn shrink_buffers(statistics: &mut Statistics, capacity: Capacity) -> Drained {
+    let mut drained = Drained::default();
+
+    let main_stats = &mut statistics.main_stats;
+    drained.main_logs = main_stats.logs.drain_oldest(capacity.main);
+    main_stats.dropped_logs += drained.main_logs.len() as u64;
+
+    for (id, ks) in statistics.keyword_stats.iter_mut() {
+        let logs = ks.keyword_logs.drain_oldest(capacity.keyword);
+        if !logs.is_empty() {
+            ks.stats.dropped_logs += logs.len() as u64;
+            main_stats.dropped_logs += logs.len() as u64;
+            drained.keyword_logs.push((*id, logs));
+        }
+    }
+
+    drained
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn events() -> Vec<(Timestamp, Event)> {
+        let log = |message: &str| json!({"type": "error", "message": message});
+        let events = vec![
+            json!({"event": "UpdateStats", "account": "shop",
+                "input": {"error_counts": 1, "running": true, "no_of_api_call_diff": 5}}),
+            json!({"event": "Resize", "account": "shop", "capacity": {"main": 3, "keyword": 2}}),
+            json!({"event": "AddLog", "account": "shop", "input": log("a")}),
+            json!({"event": "AddKeywordLog", "account": "shop", "id": 7, "input": log("b"),
+                "orphans": {"mode": "buffer", "grace_secs": 60}}),
+            json!({"event": "SetKeywords", "account": "shop", "input": [{"id": 7, "name": "k"}]}),
+            json!({"event": "StartRun", "account": "shop", "input": {"version": "1"}}),
+            json!({"event": "AddLogBatch", "account": "shop", "input": [
+                {"keyword_id": null, "type": "info", "message": "c"},
+                {"keyword_id": 7, "type": "error", "message": "d"},
+                {"keyword_id": 7, "type": "error", "message": "e"},
+                {"keyword_id": null, "type": "error", "message": "f"},
+                {"keyword_id": null, "type": "error", "message": "g"},
+            ]}),
+            json!({"event": "Heartbeat", "account": "shop", "input": {"keywords": [7]}}),
+            json!({"event": "Expire", "account": "shop",
+                "lists": [{"keyword_id": null, "indices": [0]}]}),
+            json!({"event": "EndRun", "account": "shop", "input": {"status": "ok"}}),
+            json!({"event": "Clear", "account": "shop", "count": 1}),
+        ];
+
+        let start = Timestamp::from_epoch_millis(1_600_000_000_000).unwrap();
+        events
+            .into_iter()
+            .enumerate()
+            .map(|(i, event)| {
+                let time = start.add_secs(i as i64 * 10);
+                (time, serde_json::from_value(event).unwrap())
+            })
+            .collect()
+    }
+
+    fn state(accounts: &Accounts) -> serde_json::Value {
+        serde_json::to_value(&accounts["shop"]).unwrap()
+    }
+
+    #[test]
+    fn snapshot_plus_replay_matches_full_replay() {
+        let events = events();
+
+        let mut full = Accounts::new();
+        for (time, event) in events.iter() {
+            replay(&mut full, event, *time);
+        }
+        // Log pushed out of full main buffer waits in overflow
+        assert!(!full["shop"].overflow.main_logs.is_empty());
+
+        for split in 0..=events.len() {
+            let mut accounts = Accounts::new();
+            for (time, event) in events[..split].iter() {
+                replay(&mut accounts, event, *time);
+            }
+
+            // Snapshot is json of each account
+            let snapshot = serde_json::to_string(&accounts).unwrap();
+            let mut accounts: Accounts = serde_json::from_str(&snapshot).unwrap();
+
+            for (time, event) in events[split..].iter() {
+                replay(&mut accounts, event, *time);
+            }
+
+            assert_eq!(state(&accounts), state(&full), "split at {}", split);
+        }
+    }
 }
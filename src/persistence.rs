@@ -0,0 +1,193 @@
+// Persistence of the in memory database.
+// Every account is stored as its own json file inside the data directory so a
+// corrupt file only loses one account instead of everything.
+// Changes made after a snapshot live in the journal and are replayed on boot.
+use crate::journal;
+use crate::metrics;
+use crate::models::{replay, Db, Statistics, Store};
+use crate::retention;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::time::Duration;
+use tracing::{error, info};
+
+const SNAPSHOT_EXTENSION: &str = "json";
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct Snapshot<'a> {
+    // Last journal entry which is already part of this snapshot
+    journal_seq: u64,
+    statistics: Cow<'a, Statistics>,
+}
+
+// Reads all account snapshots from data directory and replays journal on top of them.
+// If directory doesn't exist we create it and start with blank database.
+pub fn load_db(data_dir: &Path, journal_fsync: bool) -> Result<Db, String> {
+    std::fs::create_dir_all(data_dir)
+        .map_err(|e| format!("Unable to create data directory {:?}: {:?}", data_dir, e))?;
+
+    let mut accounts = HashMap::new();
+    let mut snapshot_seqs = HashMap::new();
+
+    let entries = std::fs::read_dir(data_dir)
+        .map_err(|e| format!("Unable to read data directory {:?}: {:?}", data_dir, e))?;
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+
+        if path.extension().and_then(|e| e.to_str()) != Some(SNAPSHOT_EXTENSION) {
+            continue;
+        }
+
+        let snapshot = std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| {
+                serde_json::from_slice::<Snapshot>(&content).map_err(|e| e.to_string())
+            });
+
+        match snapshot {
+            Ok(snapshot) => {
+                let journal_seq = snapshot.journal_seq;
+                let statistics = snapshot.statistics.into_owned();
+                let account = statistics.main_stats.account_name.to_owned();
+
+                // Old and new named snapshot of an account both exist if we crashed after writing
+                // one but before removing other, newer one wins
+                if snapshot_seqs
+                    .get(&account)
+                    .is_some_and(|&seq| seq > journal_seq)
+                {
+                    info!("Skipping older snapshot {:?} of {}", path, account);
+                    continue;
+                }
+                info!("Loaded account {} from {:?}", account, path);
+
+                snapshot_seqs.insert(account.clone(), journal_seq);
+                accounts.insert(account, statistics);
+            }
+            Err(e) => error!("Skipping invalid snapshot {:?}: {}", path, e),
+        }
+    }
+
+    info!("Loaded {} accounts from {:?}", accounts.len(), data_dir);
+
+    let journal_entries = journal::init(data_dir, journal_fsync)
+        .map_err(|e| format!("Unable to open journal in {:?}: {:?}", data_dir, e))?;
+
+    let mut replayed = 0;
+    for entry in journal_entries {
+        let snapshot_seq = snapshot_seqs
+            .get(entry.event.account())
+            .copied()
+            .unwrap_or(0);
+
+        if entry.seq > snapshot_seq {
+            replay(&mut accounts, &entry.event, entry.time);
+            replayed += 1;
+        }
+    }
+
+    info!("Replayed {} journal entries", replayed);
+
+    let mut total_bytes = 0;
+    for statistics in accounts.values_mut() {
+        statistics.recount_bytes();
+        total_bytes += statistics.main_stats.log_bytes;
+    }
+    retention::set_log_bytes(total_bytes);
+
+    Ok(Arc::new(Store::new(accounts)))
+}
+
+pub fn snapshot_path(data_dir: &Path, account: &str) -> PathBuf {
+    data_dir.join(crate::helpers::file_stem(account) + "." + SNAPSHOT_EXTENSION)
+}
+
+// Snapshots were once named by sanitized account name alone
+fn legacy_snapshot_path(data_dir: &Path, account: &str) -> PathBuf {
+    data_dir.join(crate::helpers::sanitize(account) + "." + SNAPSHOT_EXTENSION)
+}
+
+// Writes every account to disk and then drops journal entries covered by it.
+// Accounts are serialized one at a time under their own read lock; the actual disk io happens
+// after locks are released.
+pub async fn flush_db(db: &Db, data_dir: &Path) -> std::io::Result<()> {
+    // Accounts are added before their first event is journaled so every account with an entry up
+    // to here is listed below, and its snapshot covers at least up to here. Later entries go to a
+    // new journal segment.
+    let journal_seq = journal::rotate().await?;
+
+    let accounts = db.all();
+    let mut snapshots = Vec::with_capacity(accounts.len());
+    for (account, slot) in accounts {
+        let statistics = metrics::read_account(&slot).await;
+
+        // Writers append to journal while holding write lock of their account so nothing of this
+        // account can be appended while we hold its read lock.
+        let content = serde_json::to_vec(&Snapshot {
+            journal_seq: journal::last_seq(),
+            statistics: Cow::Borrowed(&*statistics),
+        })?;
+        snapshots.push((account.clone(), snapshot_path(data_dir, &account), content));
+    }
+
+    tokio::fs::create_dir_all(data_dir).await?;
+
+    // One failing account doesn't stop others from being written, but journal is kept whole
+    // until every account is in a snapshot
+    let mut failed = 0;
+    for (account, path, content) in snapshots.iter() {
+        if let Err(e) = write_snapshot(path, content).await {
+            error!(
+                "Unable to write snapshot of {} to {:?}: {:?}",
+                account, path, e
+            );
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        return Err(std::io::Error::other(format!(
+            "{} snapshots failed, journal is not compacted",
+            failed
+        )));
+    }
+
+    // Old named snapshot may hold another account whose name sanitized the same, so they are
+    // only removed once every account has its new one
+    for (account, _, _) in snapshots.iter() {
+        let legacy_path = legacy_snapshot_path(data_dir, account);
+        match tokio::fs::remove_file(&legacy_path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                error!("Unable to remove old snapshot {:?}: {:?}", legacy_path, e)
+            }
+            _ => {}
+        }
+    }
+
+    let data_dir = data_dir.to_owned();
+    tokio::task::spawn_blocking(move || journal::compact(&data_dir, journal_seq)).await??;
+
+    Ok(())
+}
+
+// Write to temporary file first and rename it so a crash while writing never leaves half
+// written snapshot.
+async fn write_snapshot(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension(SNAPSHOT_EXTENSION.to_owned() + ".tmp");
+    tokio::fs::write(&tmp_path, content).await?;
+    tokio::fs::rename(&tmp_path, path).await
+}
+
+pub async fn flush_database_periodically(db: Db, data_dir: PathBuf, interval: Duration) {
+    loop {
+        tokio::time::delay_for(interval).await;
+
+        if let Err(e) = flush_db(&db, &data_dir).await {
+            error!("Unable to flush database to {:?}: {:?}", data_dir, e);
+        }
+    }
+}
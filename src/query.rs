@@ -0,0 +1,521 @@
+// Filtering of main and keyword logs for `/{account}/logs`
+//
+// Example:
+//  /shop1/logs?source=keyword&min_severity=warn&contains=timeout&meta=status=500&limit=50&order=desc
+//
+// `meta` is comma separated list of predicates on dotted paths inside `Log.meta`.
+// Supported operators are `=`, `!=`, `>`, `<`, `~` (contains) and `?` (exists, no value).
+use crate::models::{KeywordId, Log, Statistics};
+use crate::severity::Severity;
+use crate::timestamp::Timestamp;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 5000;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Main,
+    Keyword,
+    All,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LogQuery {
+    pub source: Option<Source>,
+    pub keyword_id: Option<KeywordId>,
+
+    // Comma separated list of log types
+    pub r#type: Option<String>,
+
+    // Only logs at least this severe e.g `warn` gives warn, error and fatal
+    pub min_severity: Option<String>,
+
+    pub from: Option<String>,
+    pub to: Option<String>,
+
+    // Case insensitive substring of message
+    pub contains: Option<String>,
+    pub regex: Option<String>,
+
+    pub meta: Option<String>,
+
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    pub order: Option<Order>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LogItem<'a> {
+    // None for main logs
+    pub keyword_id: Option<KeywordId>,
+    #[serde(flatten)]
+    pub log: &'a Log,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LogPage<'a> {
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub logs: Vec<LogItem<'a>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum MetaOp {
+    Eq,
+    Ne,
+    Gt,
+    Lt,
+    Contains,
+    Exists,
+}
+
+#[derive(Debug, Clone)]
+struct MetaPredicate {
+    path: Vec<String>,
+    op: MetaOp,
+    value: String,
+}
+
+// Query after parsing and validation
+pub struct LogFilter {
+    source: Source,
+    keyword_id: Option<KeywordId>,
+    types: Option<Vec<Severity>>,
+    min_level: Option<u8>,
+    from: Option<Timestamp>,
+    to: Option<Timestamp>,
+    contains: Option<String>,
+    regex: Option<Regex>,
+    meta: Vec<MetaPredicate>,
+    limit: usize,
+    offset: usize,
+    order: Order,
+}
+
+impl LogFilter {
+    pub fn parse(query: &LogQuery) -> Result<LogFilter, String> {
+        let types = query.r#type.as_deref().map(Severity::parse_list);
+        let min_level = query
+            .min_severity
+            .as_deref()
+            .map(Severity::parse_min)
+            .transpose()?;
+
+        let parse_bound = |bound: &Option<String>, name: &str| match bound {
+            Some(b) => Timestamp::parse(b)
+                .map(Some)
+                .ok_or_else(|| format!("Invalid `{}` time {}", name, b)),
+            None => Ok(None),
+        };
+
+        let regex = match &query.regex {
+            Some(r) => Some(Regex::new(r).map_err(|e| format!("Invalid regex: {}", e))?),
+            None => None,
+        };
+
+        let meta = match &query.meta {
+            Some(m) => m
+                .split(',')
+                .filter(|p| !p.trim().is_empty())
+                .map(parse_meta_predicate)
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+
+        // If keyword id is given we obviously want keyword logs
+        let source = match (query.source, query.keyword_id) {
+            (Some(source), _) => source,
+            (None, Some(_)) => Source::Keyword,
+            (None, None) => Source::All,
+        };
+
+        Ok(LogFilter {
+            source,
+            keyword_id: query.keyword_id,
+            types,
+            min_level,
+            from: parse_bound(&query.from, "from")?,
+            to: parse_bound(&query.to, "to")?,
+            contains: query.contains.as_ref().map(|c| c.to_lowercase()),
+            regex,
+            meta,
+            limit: query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT),
+            offset: query.offset.unwrap_or(0),
+            order: query.order.unwrap_or(Order::Asc),
+        })
+    }
+
+    pub fn matches(&self, log: &Log) -> bool {
+        if let Some(types) = &self.types {
+            if !types.contains(&log.r#type) {
+                return false;
+            }
+        }
+
+        if let Some(min_level) = self.min_level {
+            if log.r#type.level() < min_level {
+                return false;
+            }
+        }
+
+        if self.from.is_some() || self.to.is_some() {
+            let time = log.effective_time();
+
+            if self.from.map(|from| time < from).unwrap_or(false)
+                || self.to.map(|to| time > to).unwrap_or(false)
+            {
+                return false;
+            }
+        }
+
+        if let Some(contains) = &self.contains {
+            if !log.message.to_lowercase().contains(contains.as_str()) {
+                return false;
+            }
+        }
+
+        if let Some(regex) = &self.regex {
+            if !regex.is_match(&log.message) {
+                return false;
+            }
+        }
+
+        self.meta
+            .iter()
+            .all(|predicate| predicate.matches(log.meta.as_ref()))
+    }
+
+    pub fn run<'a>(&self, statistics: &'a Statistics) -> LogPage<'a> {
+        let mut items: Vec<LogItem> = Vec::new();
+
+        if self.source != Source::Keyword {
+            items.extend(
+                statistics
+                    .main_stats
+                    .logs
+                    .iter()
+                    .filter(|log| self.matches(log))
+                    .map(|log| LogItem {
+                        keyword_id: None,
+                        log,
+                    }),
+            );
+        }
+
+        if self.source != Source::Main {
+            for (id, ks) in statistics.keyword_stats.iter() {
+                if self.keyword_id.map(|k| k != *id).unwrap_or(false) {
+                    continue;
+                }
+
+                items.extend(
+                    ks.keyword_logs
+                        .iter()
+                        .filter(|log| self.matches(log))
+                        .map(|log| LogItem {
+                            keyword_id: Some(*id),
+                            log,
+                        }),
+                );
+            }
+        }
+
+        // Logs of different keywords are interleaved by time. Sort is stable so
+        // logs with same time keep their insertion order.
+        if self.source == Source::All || (self.source == Source::Keyword && self.keyword_id.is_none()) {
+            items.sort_by_key(|item| item.log.effective_time());
+        }
+
+        if self.order == Order::Desc {
+            items.reverse();
+        }
+
+        let total = items.len();
+        let logs = items
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit)
+            .collect();
+
+        LogPage {
+            total,
+            offset: self.offset,
+            limit: self.limit,
+            logs,
+        }
+    }
+}
+
+fn parse_meta_predicate(input: &str) -> Result<MetaPredicate, String> {
+    let input = input.trim();
+
+    // First operator in input wins so values may contain operator characters.
+    // `!=` must be checked before `=`
+    let ops = [
+        ("!=", MetaOp::Ne),
+        ("=", MetaOp::Eq),
+        (">", MetaOp::Gt),
+        ("<", MetaOp::Lt),
+        ("~", MetaOp::Contains),
+    ];
+
+    for (pos, _) in input.char_indices() {
+        for (token, op) in ops.iter() {
+            if input[pos..].starts_with(token) {
+                return Ok(MetaPredicate {
+                    path: split_path(&input[..pos]),
+                    op: op.clone(),
+                    value: input[pos + token.len()..].to_owned(),
+                });
+            }
+        }
+    }
+
+    if let Some(path) = input.strip_suffix('?') {
+        return Ok(MetaPredicate {
+            path: split_path(path),
+            op: MetaOp::Exists,
+            value: String::new(),
+        });
+    }
+
+    Err(format!("Invalid meta predicate {}", input))
+}
+
+fn split_path(path: &str) -> Vec<String> {
+    path.trim().split('.').map(|s| s.to_owned()).collect()
+}
+
+impl MetaPredicate {
+    fn matches(&self, meta: Option<&Value>) -> bool {
+        let value = meta.and_then(|meta| {
+            self.path
+                .iter()
+                .try_fold(meta, |value, key| value.get(key.as_str()))
+        });
+
+        let value = match value {
+            Some(value) => value,
+            None => return false,
+        };
+
+        match self.op {
+            MetaOp::Exists => true,
+            MetaOp::Eq => value_to_string(value) == self.value,
+            MetaOp::Ne => value_to_string(value) != self.value,
+            MetaOp::Contains => value_to_string(value).contains(self.value.as_str()),
+            MetaOp::Gt | MetaOp::Lt => {
+                let (lhs, rhs) = match (value.as_f64(), self.value.parse::<f64>().ok()) {
+                    (Some(lhs), Some(rhs)) => (lhs, rhs),
+                    _ => return false,
+                };
+
+                if self.op == MetaOp::Gt {
+                    lhs > rhs
+                } else {
+                    lhs < rhs
+                }
+            }
+        }
+    }
+}
+
+// Strings are compared without quotes, everything else by their json representation
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.to_owned(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{apply_event, Event};
+    use serde_json::json;
+
+    const START: i64 = 1_600_000_000_000;
+
+    // Main logs at seconds 1 and 4, keyword 1 at 2 and 5, keyword 2 at 3
+    fn statistics() -> Statistics {
+        let time = Timestamp::from_epoch_millis(START).unwrap();
+        let mut statistics = Statistics::new("shop".to_owned(), time);
+
+        let log = |secs: i64, r#type: &str, message: &str, meta: Value| json!({"type": r#type, "message": message, "time": START + secs * 1000, "meta": meta});
+        let events = vec![
+            json!({"event": "SetKeywords", "account": "shop", "input": [{"id": 1}, {"id": 2}]}),
+            json!({"event": "AddLog", "account": "shop",
+                "input": log(1, "info", "Started", json!({"http": {"status": 200}}))}),
+            json!({"event": "AddKeywordLog", "account": "shop", "id": 1,
+                "input": log(2, "warn", "Price TIMEOUT", json!({"http": {"status": 504}}))}),
+            json!({"event": "AddKeywordLog", "account": "shop", "id": 2,
+                "input": log(3, "error", "Ad missing", Value::Null)}),
+            json!({"event": "AddLog", "account": "shop",
+                "input": log(4, "error", "Login timeout", json!({"http": {"status": 500}}))}),
+            json!({"event": "AddKeywordLog", "account": "shop", "id": 1,
+                "input": log(5, "debug", "Price 1.5", json!({"price": 1.5}))}),
+        ];
+
+        for event in events {
+            let event: Event = serde_json::from_value(event).unwrap();
+            apply_event(&mut statistics, &event, time);
+        }
+        statistics
+    }
+
+    fn filter(query: Value) -> LogFilter {
+        LogFilter::parse(&serde_json::from_value(query).unwrap()).unwrap()
+    }
+
+    fn messages(page: &LogPage) -> Vec<String> {
+        page.logs
+            .iter()
+            .map(|item| item.log.message.clone())
+            .collect()
+    }
+
+    fn secs(page: &LogPage) -> Vec<i64> {
+        page.logs
+            .iter()
+            .map(|item| (item.log.effective_time().epoch_millis() - START) / 1000)
+            .collect()
+    }
+
+    #[test]
+    fn merges_main_and_keyword_logs_by_time() {
+        let statistics = statistics();
+
+        let page = filter(json!({})).run(&statistics);
+        assert_eq!(page.total, 5);
+        assert_eq!(secs(&page), vec![1, 2, 3, 4, 5]);
+        let ids: Vec<_> = page.logs.iter().map(|item| item.keyword_id).collect();
+        assert_eq!(ids, vec![None, Some(1), Some(2), None, Some(1)]);
+
+        let page = filter(json!({"order": "desc"})).run(&statistics);
+        assert_eq!(secs(&page), vec![5, 4, 3, 2, 1]);
+
+        let page = filter(json!({"source": "main"})).run(&statistics);
+        assert_eq!(secs(&page), vec![1, 4]);
+
+        let page = filter(json!({"source": "keyword"})).run(&statistics);
+        assert_eq!(secs(&page), vec![2, 3, 5]);
+
+        // Keyword id alone means keyword logs
+        let page = filter(json!({"keyword_id": 1})).run(&statistics);
+        assert_eq!(secs(&page), vec![2, 5]);
+    }
+
+    #[test]
+    fn pages_cover_every_log_once() {
+        let statistics = statistics();
+
+        for order in ["asc", "desc"] {
+            let all = secs(&filter(json!({ "order": order })).run(&statistics));
+
+            let mut paged = Vec::new();
+            let mut offset = 0;
+            loop {
+                let query = json!({"order": order, "limit": 2, "offset": offset});
+                let page = filter(query).run(&statistics);
+                assert_eq!(page.total, 5);
+                assert_eq!((page.offset, page.limit), (offset, 2));
+                if page.logs.is_empty() {
+                    break;
+                }
+                paged.extend(secs(&page));
+                offset += page.logs.len();
+            }
+
+            assert_eq!(paged, all);
+        }
+
+        let page = filter(json!({"limit": 1_000_000})).run(&statistics);
+        assert_eq!(page.limit, MAX_LIMIT);
+        assert_eq!(filter(json!({})).run(&statistics).limit, DEFAULT_LIMIT);
+    }
+
+    #[test]
+    fn filters_by_type_time_and_text() {
+        let statistics = statistics();
+        let from = START + 2000;
+        let to = START + 4000;
+
+        let page = filter(json!({"type": "error,debug"})).run(&statistics);
+        assert_eq!(secs(&page), vec![3, 4, 5]);
+
+        let page = filter(json!({"min_severity": "warn"})).run(&statistics);
+        assert_eq!(secs(&page), vec![2, 3, 4]);
+
+        // Bounds are inclusive and take epoch millis as well as rfc3339
+        let query = json!({"from": from.to_string(), "to": Timestamp::from_epoch_millis(to).unwrap().to_rfc3339()});
+        let page = filter(query).run(&statistics);
+        assert_eq!(secs(&page), vec![2, 3, 4]);
+
+        let page = filter(json!({"contains": "timeout"})).run(&statistics);
+        assert_eq!(messages(&page), vec!["Price TIMEOUT", "Login timeout"]);
+
+        let page = filter(json!({"regex": "^Price \\d"})).run(&statistics);
+        assert_eq!(messages(&page), vec!["Price 1.5"]);
+
+        let page = filter(json!({"min_severity": "warn", "contains": "timeout", "source": "main"}))
+            .run(&statistics);
+        assert_eq!(messages(&page), vec!["Login timeout"]);
+    }
+
+    #[test]
+    fn filters_by_meta() {
+        let statistics = statistics();
+        let run = |meta: &str| secs(&filter(json!({ "meta": meta })).run(&statistics));
+
+        assert_eq!(run("http.status=500"), vec![4]);
+        assert_eq!(run("http.status!=500"), vec![1, 2]);
+        assert_eq!(run("http.status>200"), vec![2, 4]);
+        assert_eq!(run("http.status<500,http.status>200"), Vec::<i64>::new());
+        assert_eq!(run("http.status~50"), vec![2, 4]);
+        assert_eq!(run("price?"), vec![5]);
+        assert_eq!(run("price>1"), vec![5]);
+        // Logs without meta or that path never match, not even `!=`
+        assert_eq!(run("price!=2"), vec![5]);
+    }
+
+    #[test]
+    fn parses_meta_predicates() {
+        let predicate = parse_meta_predicate(" a.b != x=y ").unwrap();
+        assert_eq!(predicate.path, vec!["a", "b"]);
+        assert_eq!(predicate.op, MetaOp::Ne);
+        assert_eq!(predicate.value, " x=y");
+
+        let predicate = parse_meta_predicate("url~a>b").unwrap();
+        assert_eq!(predicate.op, MetaOp::Contains);
+        assert_eq!(predicate.value, "a>b");
+
+        assert_eq!(parse_meta_predicate("a.b?").unwrap().op, MetaOp::Exists);
+        assert!(parse_meta_predicate("a.b").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_queries() {
+        for query in [
+            json!({"min_severity": "loud"}),
+            json!({"from": "yesterday"}),
+            json!({"to": "2020-13-01T00:00:00Z"}),
+            json!({"regex": "("}),
+            json!({"meta": "status"}),
+        ] {
+            let query: LogQuery = serde_json::from_value(query.clone()).unwrap();
+            assert!(LogFilter::parse(&query).is_err(), "{:?}", query);
+        }
+    }
+}
@@ -0,0 +1,725 @@
+// Retention of logs.
+// Policies come from `[retention]` in config.toml and can be set globally, per account and
+// per severity. They are checked every `interval_secs` and whatever falls out is removed with
+// an `Event::Expire` so journal replay removes exactly the same logs.
+//
+// Limits apply to every log list on its own (main logs and each keyword's logs):
+// - `max_age_secs` drops logs received longer ago than that
+// - `max_entries` / `max_bytes` keep only the newest logs fitting in the limit
+// A limit under `severity.<name>` only counts logs of that severity. Age also falls back to
+// the general limit when severity has none. Account policies override global ones field by field.
+//
+// On top of that `memory_budget_bytes` caps bytes used by logs of all accounts together. When a
+// commit goes over it the lowest severity logs are evicted first, oldest first within a severity.
+// Eviction runs in its own task as it has to visit every account.
+use crate::archive;
+use crate::log_buffer::LogBuffer;
+use crate::metrics;
+use crate::models::{
+    commit, has_expired_orphans, Db, Event, ExpiredLogs, KeywordId, Log, Statistics,
+};
+use crate::severity::Severity;
+use crate::timestamp::Timestamp;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::Notify;
+use tokio::time::Duration;
+use tracing::{error, info, warn};
+
+// Used when config.toml has no `[retention]` section. The old clear ran every 6 hours so logs
+// lived about that long. How many are kept is left to the capacities of log buffers.
+const DEFAULT_MAX_AGE_SECS: u64 = 6 * 60 * 60;
+const DEFAULT_INTERVAL_SECS: u64 = 30;
+
+static CONFIG: OnceCell<RetentionConfig> = OnceCell::new();
+static DEFAULT_CONFIG: Lazy<RetentionConfig> = Lazy::new(RetentionConfig::default);
+
+// Bytes used by logs of all accounts, sum of every `MainStats::log_bytes`
+static LOG_BYTES: AtomicU64 = AtomicU64::new(0);
+static OVER_BUDGET: Lazy<Notify> = Lazy::new(Notify::new);
+
+pub fn init(config: RetentionConfig) {
+    let _ = CONFIG.set(config);
+}
+
+fn config() -> &'static RetentionConfig {
+    CONFIG.get().unwrap_or(&DEFAULT_CONFIG)
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct Limits {
+    pub max_entries: Option<usize>,
+    pub max_age_secs: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+impl Limits {
+    // Fields of self, falling back to `other` for those not set
+    fn or(&self, other: &Limits) -> Limits {
+        Limits {
+            max_entries: self.max_entries.or(other.max_entries),
+            max_age_secs: self.max_age_secs.or(other.max_age_secs),
+            max_bytes: self.max_bytes.or(other.max_bytes),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct Policy {
+    #[serde(flatten)]
+    pub limits: Limits,
+
+    // Keyed by severity name, see `Severity::parse`
+    #[serde(default)]
+    pub severity: HashMap<String, Limits>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RetentionConfig {
+    // How often policies are evaluated
+    pub interval_secs: Option<u64>,
+
+    // Bytes all logs together may use. Unlimited if not set
+    pub memory_budget_bytes: Option<u64>,
+
+    #[serde(flatten)]
+    pub global: Policy,
+
+    #[serde(default)]
+    pub accounts: HashMap<String, Policy>,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            interval_secs: None,
+            memory_budget_bytes: None,
+            global: Policy {
+                limits: Limits {
+                    max_age_secs: Some(DEFAULT_MAX_AGE_SECS),
+                    ..Limits::default()
+                },
+                severity: HashMap::new(),
+            },
+            accounts: HashMap::new(),
+        }
+    }
+}
+
+// Policy of one account with account and global settings merged
+#[derive(Debug, Clone)]
+pub struct ResolvedPolicy {
+    general: Limits,
+    severity: HashMap<String, Limits>,
+}
+
+impl RetentionConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS).max(1))
+    }
+
+    pub fn policy_for(&self, account: &str) -> ResolvedPolicy {
+        let mut severity = normalize(&self.global.severity);
+
+        let general = match self.accounts.get(account) {
+            Some(policy) => {
+                for (name, limits) in normalize(&policy.severity) {
+                    let merged = match severity.get(&name) {
+                        Some(global) => limits.or(global),
+                        None => limits,
+                    };
+                    severity.insert(name, merged);
+                }
+                policy.limits.or(&self.global.limits)
+            }
+            None => self.global.limits.clone(),
+        };
+
+        ResolvedPolicy { general, severity }
+    }
+}
+
+// `warning` and `warn` are the same severity
+fn normalize(severity: &HashMap<String, Limits>) -> HashMap<String, Limits> {
+    severity
+        .iter()
+        .map(|(name, limits)| (Severity::parse(name).as_str().to_owned(), limits.clone()))
+        .collect()
+}
+
+impl ResolvedPolicy {
+    fn max_age_secs(&self, severity: &Severity) -> Option<u64> {
+        self.severity
+            .get(severity.as_str())
+            .and_then(|l| l.max_age_secs)
+            .or(self.general.max_age_secs)
+    }
+
+    // Indices (ascending) of logs in `logs` which this policy no longer keeps.
+    // `logs` is oldest first like every log list in database.
+    pub fn expired(&self, logs: &LogBuffer, now: Timestamp) -> Vec<usize> {
+        let mut expired = vec![false; logs.len()];
+
+        for (i, log) in logs.iter().enumerate() {
+            if let Some(max_age) = self.max_age_secs(&log.r#type) {
+                if now.secs_since(log.received_at) > max_age as i64 {
+                    expired[i] = true;
+                }
+            }
+        }
+
+        // Count and size limits keep the newest logs so walk backwards
+        let mut per_severity: HashMap<&str, (usize, u64)> = HashMap::new();
+        for (i, log) in logs.iter().enumerate().rev() {
+            if expired[i] {
+                continue;
+            }
+
+            if let Some(limits) = self.severity.get(log.r#type.as_str()) {
+                let used = per_severity.entry(log.r#type.as_str()).or_default();
+                if exceeds(limits, used, log) {
+                    expired[i] = true;
+                }
+            }
+        }
+
+        let mut used = (0, 0);
+        for (i, log) in logs.iter().enumerate().rev() {
+            if !expired[i] && exceeds(&self.general, &mut used, log) {
+                expired[i] = true;
+            }
+        }
+
+        expired
+            .iter()
+            .enumerate()
+            .filter(|(_, expired)| **expired)
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+// Adds log to `used` (entries, bytes) and tells if that goes over limits.
+// Logs over the limit are not added so older logs are judged on what is really kept.
+fn exceeds(limits: &Limits, used: &mut (usize, u64), log: &Log) -> bool {
+    let bytes = log.approx_bytes();
+
+    let over_entries = limits.max_entries.is_some_and(|max| used.0 + 1 > max);
+    let over_bytes = limits.max_bytes.is_some_and(|max| used.1 + bytes > max);
+
+    if over_entries || over_bytes {
+        return true;
+    }
+
+    used.0 += 1;
+    used.1 += bytes;
+    false
+}
+
+// Logs of account which policy no longer keeps, as stored in `Event::Expire`
+pub fn expired_logs(
+    policy: &ResolvedPolicy,
+    statistics: &Statistics,
+    now: Timestamp,
+) -> Vec<ExpiredLogs> {
+    let mut expired = Vec::new();
+
+    let indices = policy.expired(&statistics.main_stats.logs, now);
+    if !indices.is_empty() {
+        expired.push(ExpiredLogs {
+            keyword_id: None,
+            indices,
+        });
+    }
+
+    for (id, ks) in statistics.keyword_stats.iter() {
+        let indices = policy.expired(&ks.keyword_logs, now);
+        if !indices.is_empty() {
+            expired.push(ExpiredLogs {
+                keyword_id: Some(*id),
+                indices,
+            });
+        }
+    }
+
+    expired
+}
+
+pub async fn enforce_periodically(db: Db) {
+    let config = config();
+    let interval = config.interval();
+
+    loop {
+        tokio::time::delay_for(interval).await;
+
+        for (account, slot) in db.all() {
+            let policy = config.policy_for(&account);
+
+            // Scanned under read lock so bots writing to account aren't held up when nothing
+            // has to go, which is most sweeps
+            {
+                let statistics = metrics::read_account(&slot).await;
+                let now = Timestamp::now();
+                if statistics.archive_queue.is_empty()
+                    && statistics.overflow.is_empty()
+                    && !has_stale_history(&statistics, now)
+                    && !has_expired_orphans(&statistics, now)
+                    && expired_logs(&policy, &statistics, now).is_empty()
+                {
+                    continue;
+                }
+            }
+
+            let mut statistics = metrics::write_account(&slot).await;
+
+            // Logs pushed out of full buffers since last sweep, and retry of logs whose archive
+            // write failed earlier
+            statistics.queue_overflow();
+            archive::store(&account, &mut statistics.archive_queue).await;
+
+            let now = Timestamp::now();
+            for ks in statistics.keyword_stats.values_mut() {
+                ks.history.compact(now);
+            }
+
+            // Account may have changed since the scan so indices are picked again.
+            // Expired orphans go with any event, even one without logs, so an account whose bot
+            // went quiet doesn't keep them.
+            let lists = expired_logs(&policy, &statistics, now);
+            if lists.is_empty() && !has_expired_orphans(&statistics, now) {
+                continue;
+            }
+
+            let count: usize = lists.iter().map(|l| l.indices.len()).sum();
+            if count > 0 {
+                info!("Retention expired {} logs of {}", count, account);
+            }
+
+            let event = Event::Expire {
+                account: account.clone(),
+                lists,
+            };
+            if let Err(e) = commit(&mut statistics, event).await {
+                error!("Unable to expire logs of {}: {:?}", account, e);
+            }
+        }
+    }
+}
+
+// History is compacted when keyword changes, this catches keywords which stopped changing.
+// Compaction gives the same result whenever it runs so it isn't journaled.
+fn has_stale_history(statistics: &Statistics, now: Timestamp) -> bool {
+    statistics
+        .keyword_stats
+        .values()
+        .any(|ks| ks.history.needs_compaction(now))
+}
+
+fn is_over_budget(total: u64) -> bool {
+    config().memory_budget_bytes.is_some_and(|budget| total > budget)
+}
+
+// Total after loading database
+pub fn set_log_bytes(total: u64) {
+    LOG_BYTES.store(total, Ordering::SeqCst);
+    if is_over_budget(total) {
+        OVER_BUDGET.notify();
+    }
+}
+
+// Called by `commit` with log bytes of the account before and after the event
+pub fn track_bytes(before: u64, after: u64) {
+    let total = if after >= before {
+        LOG_BYTES.fetch_add(after - before, Ordering::SeqCst) + (after - before)
+    } else {
+        LOG_BYTES.fetch_sub(before - after, Ordering::SeqCst) - (before - after)
+    };
+
+    if after > before && is_over_budget(total) {
+        OVER_BUDGET.notify();
+    }
+}
+
+// Each log list of an account, main logs first
+fn log_lists(statistics: &Statistics) -> impl Iterator<Item = (Option<KeywordId>, &LogBuffer)> {
+    std::iter::once((None, &statistics.main_stats.logs)).chain(
+        statistics
+            .keyword_stats
+            .iter()
+            .map(|(id, ks)| (Some(*id), &ks.keyword_logs)),
+    )
+}
+
+// Evicts logs whenever a commit goes over memory budget until everything fits again
+pub async fn enforce_budget(db: Db) {
+    let budget = match config().memory_budget_bytes {
+        Some(budget) => budget,
+        None => return,
+    };
+
+    loop {
+        OVER_BUDGET.notified().await;
+
+        let total = LOG_BYTES.load(Ordering::SeqCst);
+        if total <= budget {
+            continue;
+        }
+
+        // Go a bit below budget so we aren't evicting on every new log
+        let target = budget / 10 * 9;
+        let need = total - target;
+
+        // Accounts are read one at a time, so this only decides how many bytes each account
+        // gives up. Exact logs are picked again under the account's write lock.
+        let slots = db.all();
+        let mut levels: BTreeMap<u8, Vec<(Timestamp, u64, usize)>> = BTreeMap::new();
+        for (n, (_, slot)) in slots.iter().enumerate() {
+            let statistics = metrics::read_account(slot).await;
+            for (_, logs) in log_lists(&statistics) {
+                for log in logs.iter() {
+                    let candidate = (log.received_at, log.approx_bytes(), n);
+                    levels
+                        .entry(log.r#type.level())
+                        .or_default()
+                        .push(candidate);
+                }
+            }
+        }
+
+        // Severities which go completely are taken as they are, only the one where `need` is
+        // reached has to be sorted by age
+        let mut quotas = vec![0; slots.len()];
+        let mut freed = 0;
+        for (_, mut candidates) in levels {
+            if freed >= need {
+                break;
+            }
+
+            let bytes: u64 = candidates.iter().map(|c| c.1).sum();
+            if freed + bytes > need {
+                candidates.sort_unstable_by_key(|c| c.0);
+            }
+
+            for (_, bytes, n) in candidates {
+                if freed >= need {
+                    break;
+                }
+
+                freed += bytes;
+                quotas[n] += bytes;
+            }
+        }
+
+        warn!(
+            "Logs use {} bytes which is over memory budget of {}, evicting {} bytes",
+            total, budget, freed
+        );
+
+        for ((account, slot), quota) in slots.into_iter().zip(quotas) {
+            if quota == 0 {
+                continue;
+            }
+
+            let mut statistics = metrics::write_account(&slot).await;
+
+            // Other evictions and clears may have freed enough since the scan
+            let excess = LOG_BYTES.load(Ordering::SeqCst).saturating_sub(target);
+            if excess == 0 {
+                break;
+            }
+
+            let lists = evictions(&statistics, quota.min(excess));
+            if lists.is_empty() {
+                continue;
+            }
+
+            let event = Event::Expire {
+                account: account.clone(),
+                lists,
+            };
+            if let Err(e) = commit(&mut statistics, event).await {
+                error!("Unable to evict logs of {}: {:?}", account, e);
+            }
+        }
+    }
+}
+
+// Lowest severity and oldest logs of account which together free at least `bytes`
+fn evictions(statistics: &Statistics, bytes: u64) -> Vec<ExpiredLogs> {
+    let mut candidates: Vec<(u8, Timestamp, u64, usize, usize)> = Vec::new();
+    let lists: Vec<_> = log_lists(statistics).collect();
+
+    for (list, (_, logs)) in lists.iter().enumerate() {
+        candidates.extend(logs.iter().enumerate().map(|(index, log)| {
+            let bytes = log.approx_bytes();
+            (log.r#type.level(), log.received_at, bytes, list, index)
+        }));
+    }
+
+    candidates.sort_by_key(|c| (c.0, c.1));
+
+    let mut indices = vec![Vec::new(); lists.len()];
+    let mut freed = 0;
+    for (_, _, size, list, index) in candidates {
+        if freed >= bytes {
+            break;
+        }
+
+        freed += size;
+        indices[list].push(index);
+    }
+
+    lists
+        .iter()
+        .zip(indices)
+        .filter(|(_, indices)| !indices.is_empty())
+        .map(|((keyword_id, _), mut indices)| {
+            indices.sort_unstable();
+            ExpiredLogs {
+                keyword_id: *keyword_id,
+                indices,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{apply_event, bytes_of};
+    use serde_json::{json, Value};
+
+    const START: i64 = 1_600_000_000_000;
+
+    fn at(secs: i64) -> Timestamp {
+        Timestamp::from_epoch_millis(START + secs * 1000).unwrap()
+    }
+
+    // Logs given as (keyword id, received after seconds, type), keyword 1 is registered
+    fn statistics(logs: &[(Option<KeywordId>, i64, &str)]) -> Statistics {
+        let mut statistics = Statistics::new("shop".to_owned(), at(0));
+        let mut events = vec![(
+            0,
+            json!({"event": "SetKeywords", "account": "shop", "input": [{"id": 1}]}),
+        )];
+
+        for (keyword_id, secs, r#type) in logs {
+            let input = json!({"type": r#type, "message": format!("{} at {}", r#type, secs)});
+            let event = match keyword_id {
+                Some(id) => {
+                    json!({"event": "AddKeywordLog", "account": "shop", "id": id, "input": input})
+                }
+                None => json!({"event": "AddLog", "account": "shop", "input": input}),
+            };
+            events.push((*secs, event));
+        }
+
+        for (secs, event) in events {
+            let event: Event = serde_json::from_value(event).unwrap();
+            apply_event(&mut statistics, &event, at(secs));
+        }
+        statistics
+    }
+
+    fn main_logs(logs: &[(i64, &str)]) -> Statistics {
+        let logs: Vec<_> = logs.iter().map(|(secs, t)| (None, *secs, *t)).collect();
+        statistics(&logs)
+    }
+
+    fn resolve(config: Value) -> ResolvedPolicy {
+        serde_json::from_value::<RetentionConfig>(config)
+            .unwrap()
+            .policy_for("shop")
+    }
+
+    fn lists(expired: &[ExpiredLogs]) -> Vec<(Option<KeywordId>, Vec<usize>)> {
+        expired
+            .iter()
+            .map(|list| (list.keyword_id, list.indices.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn account_policy_overrides_global_field_by_field() {
+        let config: RetentionConfig = serde_json::from_value(json!({
+            "max_age_secs": 100,
+            "max_entries": 10,
+            "severity": {"warning": {"max_age_secs": 1000}, "error": {"max_bytes": 50}},
+            "accounts": {
+                "shop": {"max_entries": 5, "severity": {"warn": {"max_entries": 2}}},
+            },
+        }))
+        .unwrap();
+
+        let shop = config.policy_for("shop");
+        assert_eq!(
+            shop.general,
+            Limits {
+                max_entries: Some(5),
+                max_age_secs: Some(100),
+                max_bytes: None,
+            }
+        );
+        assert_eq!(
+            shop.severity["warn"],
+            Limits {
+                max_entries: Some(2),
+                max_age_secs: Some(1000),
+                max_bytes: None,
+            }
+        );
+        assert_eq!(shop.severity["error"].max_bytes, Some(50));
+
+        let other = config.policy_for("other");
+        assert_eq!(other.general.max_entries, Some(10));
+        assert_eq!(other.severity["warn"].max_entries, None);
+    }
+
+    #[test]
+    fn default_keeps_logs_for_six_hours() {
+        let policy = RetentionConfig::default().policy_for("shop");
+        let age = DEFAULT_MAX_AGE_SECS as i64;
+
+        let mut logs = vec![(0, "info"), (1, "error")];
+        logs.extend((0..500).map(|_| (2, "debug")));
+        let statistics = main_logs(&logs);
+
+        let logs = &statistics.main_stats.logs;
+        assert_eq!(policy.expired(logs, at(age)), Vec::<usize>::new());
+        assert_eq!(policy.expired(logs, at(age + 1)), vec![0]);
+        assert_eq!(policy.expired(logs, at(age + 2)), vec![0, 1]);
+    }
+
+    #[test]
+    fn severity_age_falls_back_to_general() {
+        let policy = resolve(json!({
+            "max_age_secs": 100,
+            "severity": {"error": {"max_age_secs": 1000}, "debug": {"max_entries": 10}},
+        }));
+        let statistics = main_logs(&[(0, "info"), (0, "error"), (0, "debug"), (450, "info")]);
+
+        let expired = policy.expired(&statistics.main_stats.logs, at(500));
+        assert_eq!(expired, vec![0, 2]);
+    }
+
+    #[test]
+    fn severity_limits_keep_newest_before_general_ones() {
+        let types = ["info", "info", "info", "error", "info", "error"];
+        let logs: Vec<_> = types
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (i as i64, *t))
+            .collect();
+        let statistics = main_logs(&logs);
+        let logs = &statistics.main_stats.logs;
+
+        let policy = resolve(json!({"max_entries": 3, "severity": {"info": {"max_entries": 1}}}));
+        assert_eq!(policy.expired(logs, at(10)), vec![0, 1, 2]);
+
+        // Logs removed for their severity don't use up general limit
+        let policy = resolve(json!({"max_entries": 2, "severity": {"info": {"max_entries": 1}}}));
+        assert_eq!(policy.expired(logs, at(10)), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn byte_limits_keep_newest_that_fit() {
+        let statistics = main_logs(&[(0, "info"), (1, "info"), (2, "info")]);
+        let logs = &statistics.main_stats.logs;
+        let size = logs.iter().next().unwrap().approx_bytes();
+
+        let policy = resolve(json!({ "max_bytes": size * 2 }));
+        assert_eq!(policy.expired(logs, at(10)), vec![0]);
+
+        let policy = resolve(json!({ "max_bytes": size * 2 - 1 }));
+        assert_eq!(policy.expired(logs, at(10)), vec![0, 1]);
+    }
+
+    #[test]
+    fn expired_logs_lists_each_log_list() {
+        let statistics = statistics(&[
+            (None, 0, "info"),
+            (Some(1), 0, "info"),
+            (None, 90, "info"),
+            (Some(1), 10, "info"),
+            (Some(1), 95, "info"),
+        ]);
+
+        let policy = resolve(json!({"max_age_secs": 50}));
+        let expired = expired_logs(&policy, &statistics, at(100));
+        assert_eq!(
+            lists(&expired),
+            vec![(None, vec![0]), (Some(1), vec![0, 1])]
+        );
+
+        let policy = resolve(json!({"max_age_secs": 500}));
+        assert!(expired_logs(&policy, &statistics, at(100)).is_empty());
+    }
+
+    #[test]
+    fn evicts_lowest_severity_oldest_first() {
+        let statistics = statistics(&[
+            (None, 0, "error"),
+            (Some(1), 1, "debug"),
+            (None, 2, "debug"),
+            (None, 1, "info"),
+            (Some(1), 3, "info"),
+        ]);
+        let size = |keyword_id: Option<KeywordId>, index: usize| {
+            let logs = match keyword_id {
+                Some(id) => &statistics.keyword_stats[&id].keyword_logs,
+                None => &statistics.main_stats.logs,
+            };
+            logs.iter().nth(index).unwrap().approx_bytes()
+        };
+        let debug = size(Some(1), 0) + size(None, 1);
+
+        let evicted = evictions(&statistics, 1);
+        assert_eq!(lists(&evicted), vec![(Some(1), vec![0])]);
+
+        let evicted = evictions(&statistics, debug);
+        assert_eq!(lists(&evicted), vec![(None, vec![1]), (Some(1), vec![0])]);
+
+        // Older info goes before newer one, errors last
+        let evicted = evictions(&statistics, debug + 1);
+        assert_eq!(
+            lists(&evicted),
+            vec![(None, vec![1, 2]), (Some(1), vec![0])]
+        );
+
+        let evicted = evictions(&statistics, u64::MAX);
+        assert_eq!(
+            lists(&evicted),
+            vec![(None, vec![0, 1, 2]), (Some(1), vec![0, 1])]
+        );
+    }
+
+    #[test]
+    fn evicted_bytes_are_taken_off_counters() {
+        let mut statistics = statistics(&[
+            (None, 0, "debug"),
+            (Some(1), 1, "debug"),
+            (None, 2, "error"),
+            (Some(1), 3, "error"),
+        ]);
+        let total: u64 = log_lists(&statistics).map(|(_, logs)| bytes_of(logs)).sum();
+        assert_eq!(statistics.main_stats.log_bytes, total);
+
+        let expired = evictions(&statistics, 1);
+        let event = Event::Expire {
+            account: "shop".to_owned(),
+            lists: expired,
+        };
+        let drained = apply_event(&mut statistics, &event, at(10)).unwrap();
+
+        let freed = bytes_of(&drained.main_logs)
+            + bytes_of(drained.keyword_logs.iter().flat_map(|(_, logs)| logs));
+        // Oldest debug log is a main one
+        assert_eq!(drained.main_logs.len(), 1);
+        assert_eq!(statistics.main_stats.log_bytes, total - freed);
+
+        let keyword = &statistics.keyword_stats[&1];
+        assert_eq!(keyword.stats.log_bytes, bytes_of(&keyword.keyword_logs));
+    }
+}
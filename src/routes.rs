@@ -1,18 +1,52 @@
+use crate::api_calls::ApiCallsQuery;
+use crate::auth::{self, Scope};
+use crate::compression;
 use crate::controllers;
-use crate::models::Db;
+use crate::errors;
+use crate::events::TailQuery;
+use crate::history::HistoryQuery;
+use crate::issues::IssuesQuery;
+use crate::metrics;
+use crate::models::{ClearQuery, Db};
+use crate::query::LogQuery;
+use crate::runs::RunsQuery;
+use crate::severity::SeverityQuery;
+use crate::timestamp::RenderQuery;
 use warp::Filter;
 
 pub fn all(db: Db) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    list_accounts(db.clone())
+    let api = list_accounts(db.clone())
+        .or(metrics(db.clone()))
         .or(clear_stat(db.clone()))
                 .or(clear_stat_full(db.clone()))
         .or(get_main_stats(db.clone()))
+        .or(stats_feed(db.clone()))
         .or(update_stats(db.clone()))
+        .or(heartbeat(db.clone()))
+        .or(start_run(db.clone()))
+        .or(end_run(db.clone()))
+        .or(list_runs(db.clone()))
         .or(add_logs_to_stats(db.clone()))
+        .or(add_logs_batch(db.clone()))
         .or(update_keyword_stats(db.clone()))
         .or(add_logs_to_keywords(db.clone()))
         .or(set_keywords_to_stats(db.clone()))
         .or(get_keyword_logs(db.clone()))
+        .or(get_keyword_history(db.clone()))
+        .or(get_api_calls(db.clone()))
+        .or(list_issues(db.clone()))
+        .or(get_issue(db.clone()))
+        .or(query_logs(db.clone()))
+        .or(tail_logs(db.clone()))
+        .or(list_archives())
+        .or(download_archive())
+        .recover(errors::handle_rejection);
+
+    // Responses are compressed as negotiated by Accept-Encoding
+    warp::header::optional::<String>("accept-encoding")
+        .and(api)
+        .and_then(compression::compress_reply)
+        .with(warp::log::custom(metrics::observe_request))
         .with(warp::trace::named("All Routes"))
 }
 
@@ -21,29 +55,46 @@ pub fn list_accounts(
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("list_accounts")
         .and(warp::get())
+        .and(auth::access(Scope::Read))
         .and(with_db(db))
         .and_then(controllers::list_accounts)
         .with(warp::trace::named("Route:Index Stats"))
 }
 
+// Prometheus scrape target. Scrapers send key as bearer token like any other client.
+pub fn metrics(db: Db) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .and(auth::access(Scope::Read))
+        .and(with_db(db))
+        .and_then(controllers::metrics)
+        .with(warp::trace::named("Route: Metrics"))
+}
+
 pub fn clear_stat(
     db: Db,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!(String / "clear_log")
-        .and(warp::get())
+        .and(warp::delete())
+        .and(auth::authorize(Scope::Write))
+        .and(warp::query::<ClearQuery>())
+        .and(auth::caller())
         .and(with_db(db))
         .and_then(controllers::clear_log)
-        .with(warp::trace::named("Route:Index Stats"))
+        .with(warp::trace::named("Route: Clear Log"))
 }
 
 pub fn clear_stat_full(
     db: Db,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!(String / "clear_log_full")
-        .and(warp::get())
+        .and(warp::delete())
+        .and(auth::authorize(Scope::Write))
+        .and(warp::query::<ClearQuery>())
+        .and(auth::caller())
         .and(with_db(db))
         .and_then(controllers::clear_log_full)
-        .with(warp::trace::named("Route:Index Stats"))
+        .with(warp::trace::named("Route: Clear Log Full"))
 }
 
 pub fn get_main_stats(
@@ -51,39 +102,117 @@ pub fn get_main_stats(
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!(String / "stats")
         .and(warp::get())
+        .and(auth::authorize(Scope::Read))
+        .and(warp::query::<SeverityQuery>())
+        .and(warp::query::<RenderQuery>())
         .and(with_db(db))
         .and_then(controllers::get_main_stats)
         .with(warp::trace::named("Route:Index Stats"))
 }
 
+pub fn stats_feed(
+    db: Db,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(String / "stats" / "ws")
+        .and(auth::authorize(Scope::Read))
+        .and(warp::ws())
+        .and(with_db(db))
+        .and_then(controllers::stats_feed)
+        .with(warp::trace::named("Route: Stats Feed"))
+}
+
 pub fn update_stats(
     db: Db,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!(String / "stats")
         .and(warp::post())
-        .and(warp::filters::body::json())
+        .and(auth::authorize(Scope::Write))
+        .and(compression::json_body())
         .and(with_db(db))
         .and_then(controllers::update_stats)
         .with(warp::trace::named("Route: Update Stats"))
 }
 
+pub fn heartbeat(
+    db: Db,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(String / "heartbeat")
+        .and(warp::post())
+        .and(auth::authorize(Scope::Write))
+        .and(compression::decoded_body())
+        .and(with_db(db))
+        .and_then(controllers::heartbeat)
+        .with(warp::trace::named("Route: Heartbeat"))
+}
+
+pub fn start_run(
+    db: Db,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(String / "runs" / "start")
+        .and(warp::post())
+        .and(auth::authorize(Scope::Write))
+        .and(compression::json_body())
+        .and(with_db(db))
+        .and_then(controllers::start_run)
+        .with(warp::trace::named("Route: Start Run"))
+}
+
+pub fn end_run(
+    db: Db,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(String / "runs" / "end")
+        .and(warp::post())
+        .and(auth::authorize(Scope::Write))
+        .and(compression::json_body())
+        .and(with_db(db))
+        .and_then(controllers::end_run)
+        .with(warp::trace::named("Route: End Run"))
+}
+
+pub fn list_runs(
+    db: Db,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(String / "runs")
+        .and(warp::get())
+        .and(auth::authorize(Scope::Read))
+        .and(warp::query::<RunsQuery>())
+        .and(warp::query::<RenderQuery>())
+        .and(with_db(db))
+        .and_then(controllers::list_runs)
+        .with(warp::trace::named("Route: List Runs"))
+}
+
 pub fn add_logs_to_stats(
     db: Db,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!(String / "stats" / "add_logs")
         .and(warp::post())
-        .and(warp::filters::body::json())
+        .and(auth::authorize(Scope::Write))
+        .and(compression::json_body())
         .and(with_db(db))
         .and_then(controllers::add_logs_to_stats)
         .with(warp::trace::named("Route: Update Stat"))
 }
 
+pub fn add_logs_batch(
+    db: Db,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(String / "logs" / "batch")
+        .and(warp::post())
+        .and(auth::authorize(Scope::Write))
+        .and(compression::decoded_body())
+        .and(with_db(db))
+        .and_then(controllers::add_logs_batch)
+        .with(warp::trace::named("Route: Add Logs Batch"))
+}
+
 pub fn set_keywords_to_stats(
     db: Db,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!(String / "stats" / "set_keywords")
         .and(warp::post())
-        .and(warp::filters::body::json())
+        .and(auth::authorize(Scope::Write))
+        .and(compression::json_body())
         .and(with_db(db))
         .and_then(controllers::set_keywords_to_stats)
         .with(warp::trace::named("Route: Set Keyword to Stat"))
@@ -94,7 +223,8 @@ pub fn update_keyword_stats(
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!(String / "update-keyword-stats")
         .and(warp::post())
-        .and(warp::filters::body::json())
+        .and(auth::authorize(Scope::Write))
+        .and(compression::json_body())
         .and(with_db(db))
         .and_then(controllers::update_keyword_stat)
         .with(warp::trace::named("Route: Update Keyword Statsitics "))
@@ -105,7 +235,8 @@ pub fn add_logs_to_keywords(
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!(String / "stats" / u64 / "add_log")
         .and(warp::post())
-        .and(warp::filters::body::json())
+        .and(auth::authorize(Scope::Write))
+        .and(compression::json_body())
         .and(with_db(db))
         .and_then(controllers::add_logs_to_keyword)
         .with(warp::trace::named("Route: Add Log to Keywords "))
@@ -116,11 +247,109 @@ pub fn get_keyword_logs(
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!(String / "keywords" / u64 / "logs")
         .and(warp::get())
+        .and(auth::authorize(Scope::Read))
+        .and(warp::query::<SeverityQuery>())
+        .and(warp::query::<RenderQuery>())
         .and(with_db(db))
         .and_then(controllers::get_keyword_logs)
         .with(warp::trace::named("Route: Get Keyword Logs "))
 }
 
+pub fn get_keyword_history(
+    db: Db,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(String / "keywords" / u64 / "history")
+        .and(warp::get())
+        .and(auth::authorize(Scope::Read))
+        .and(warp::query::<HistoryQuery>())
+        .and(warp::query::<RenderQuery>())
+        .and(with_db(db))
+        .and_then(controllers::get_keyword_history)
+        .with(warp::trace::named("Route: Get Keyword History"))
+}
+
+pub fn get_api_calls(
+    db: Db,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(String / "api_calls")
+        .and(warp::get())
+        .and(auth::authorize(Scope::Read))
+        .and(warp::query::<ApiCallsQuery>())
+        .and(warp::query::<RenderQuery>())
+        .and(with_db(db))
+        .and_then(controllers::get_api_calls)
+        .with(warp::trace::named("Route: Get Api Calls"))
+}
+
+pub fn list_issues(
+    db: Db,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(String / "issues")
+        .and(warp::get())
+        .and(auth::authorize(Scope::Read))
+        .and(warp::query::<IssuesQuery>())
+        .and(warp::query::<RenderQuery>())
+        .and(with_db(db))
+        .and_then(controllers::list_issues)
+        .with(warp::trace::named("Route: List Issues"))
+}
+
+pub fn get_issue(
+    db: Db,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(String / "issues" / String)
+        .and(warp::get())
+        .and(auth::authorize(Scope::Read))
+        .and(warp::query::<RenderQuery>())
+        .and(with_db(db))
+        .and_then(controllers::get_issue)
+        .with(warp::trace::named("Route: Get Issue"))
+}
+
+pub fn query_logs(
+    db: Db,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(String / "logs")
+        .and(warp::get())
+        .and(auth::authorize(Scope::Read))
+        .and(warp::query::<LogQuery>())
+        .and(warp::query::<RenderQuery>())
+        .and(with_db(db))
+        .and_then(controllers::query_logs)
+        .with(warp::trace::named("Route: Query Logs"))
+}
+
+pub fn tail_logs(
+    db: Db,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(String / "logs" / "tail")
+        .and(warp::get())
+        .and(auth::authorize(Scope::Read))
+        .and(warp::query::<TailQuery>())
+        .and(warp::query::<RenderQuery>())
+        .and(warp::sse::last_event_id::<u64>())
+        .and(with_db(db))
+        .and_then(controllers::tail_logs)
+        .with(warp::trace::named("Route: Tail Logs"))
+}
+
+pub fn list_archives() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(String / "archives")
+        .and(warp::get())
+        .and(auth::authorize(Scope::Read))
+        .and_then(controllers::list_archives)
+        .with(warp::trace::named("Route: List Archives"))
+}
+
+pub fn download_archive(
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(String / "archives" / String)
+        .and(warp::get())
+        .and(auth::authorize(Scope::Read))
+        .and_then(controllers::download_archive)
+        .with(warp::trace::named("Route: Download Archive"))
+}
+
 fn with_db(db: Db) -> impl Filter<Extract = (Db,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || db.clone())
 }
@@ -0,0 +1,147 @@
+// Runs of a bot.
+// Bot posts to `/{account}/runs/start` when it starts and `/{account}/runs/end` when it exits.
+// While a run is open logs, errors and api calls of the account are also counted on the run.
+// Starting a run while another is open ends the open one as `abandoned` (bot crashed or was killed).
+// Only last `MAX_RUNS` finished runs are kept.
+use crate::severity::{Severity, SeverityCounts};
+use crate::timestamp::Timestamp;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+
+const MAX_RUNS: usize = 100;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct StartRun {
+    pub version: Option<String>,
+    pub host: Option<String>,
+    // Anything else bot wants to remember about the run
+    pub meta: Option<Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EndRun {
+    // e.g. `ok`, `error`, `killed`
+    pub status: String,
+    pub code: Option<i64>,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RunCounters {
+    pub log_counts: u64,
+    pub error_counts: u64,
+    pub severity_counts: SeverityCounts,
+    pub no_api_calls: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Run {
+    // Increases by one per account
+    pub id: u64,
+    pub started_at: Timestamp,
+    pub ended_at: Option<Timestamp>,
+    pub version: Option<String>,
+    pub host: Option<String>,
+    pub meta: Option<Value>,
+    // None while run is open
+    pub exit: Option<EndRun>,
+    pub counters: RunCounters,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct Runs {
+    pub current: Option<Run>,
+    // Finished runs, oldest first
+    pub history: VecDeque<Run>,
+    pub last_run_id: u64,
+}
+
+impl Runs {
+    pub fn start(&mut self, input: &StartRun, time: Timestamp) {
+        if self.current.is_some() {
+            self.end(
+                &EndRun {
+                    status: "abandoned".to_owned(),
+                    code: None,
+                    message: Some("A new run was started before this one ended".to_owned()),
+                },
+                time,
+            );
+        }
+
+        self.last_run_id += 1;
+        self.current = Some(Run {
+            id: self.last_run_id,
+            started_at: time,
+            ended_at: None,
+            version: input.version.clone(),
+            host: input.host.clone(),
+            meta: input.meta.clone(),
+            exit: None,
+            counters: RunCounters::default(),
+        });
+    }
+
+    // Does nothing if no run is open
+    pub fn end(&mut self, input: &EndRun, time: Timestamp) {
+        if let Some(mut run) = self.current.take() {
+            run.ended_at = Some(time);
+            run.exit = Some(input.clone());
+            self.history.push_back(run);
+
+            while self.history.len() > MAX_RUNS {
+                self.history.pop_front();
+            }
+        }
+    }
+
+    pub fn count_log(&mut self, severity: &Severity) {
+        if let Some(run) = self.current.as_mut() {
+            run.counters.log_counts += 1;
+            run.counters.severity_counts.add(severity);
+            if severity.is_error() {
+                run.counters.error_counts += 1;
+            }
+        }
+    }
+
+    pub fn count_errors(&mut self, errors: u64) {
+        if let Some(run) = self.current.as_mut() {
+            run.counters.error_counts += errors;
+        }
+    }
+
+    pub fn count_api_calls(&mut self, calls: u64) {
+        if let Some(run) = self.current.as_mut() {
+            run.counters.no_api_calls += calls;
+        }
+    }
+}
+
+// `?limit=..` of `/{account}/runs`
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RunsQuery {
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunsPage<'a> {
+    pub current: Option<&'a Run>,
+    // Newest first
+    pub runs: Vec<&'a Run>,
+}
+
+impl RunsQuery {
+    pub fn run<'a>(&self, runs: &'a Runs) -> RunsPage<'a> {
+        RunsPage {
+            current: runs.current.as_ref(),
+            runs: runs
+                .history
+                .iter()
+                .rev()
+                .take(self.limit.unwrap_or(MAX_RUNS))
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+// Severity of a log.
+// Bot sends it as free form `type` string so parsing is tolerant: case is ignored,
+// common aliases are understood and anything else is kept as it is.
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Severity {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Fatal,
+    Unknown(String),
+}
+
+impl Severity {
+    pub fn parse(s: &str) -> Severity {
+        match s.trim().to_lowercase().as_str() {
+            "trace" | "verbose" => Severity::Trace,
+            "debug" | "dbg" => Severity::Debug,
+            "info" | "information" | "notice" | "log" => Severity::Info,
+            "warn" | "warning" => Severity::Warn,
+            "error" | "err" | "failure" | "failed" => Severity::Error,
+            "fatal" | "critical" | "crit" | "panic" | "emergency" => Severity::Fatal,
+            _ => Severity::Unknown(s.to_owned()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Severity::Trace => "trace",
+            Severity::Debug => "debug",
+            Severity::Info => "info",
+            Severity::Warn => "warn",
+            Severity::Error => "error",
+            Severity::Fatal => "fatal",
+            Severity::Unknown(s) => s,
+        }
+    }
+
+    // Unknown severities rank like info so `min_severity=warn` hides them
+    pub fn level(&self) -> u8 {
+        match self {
+            Severity::Trace => 0,
+            Severity::Debug => 1,
+            Severity::Info | Severity::Unknown(_) => 2,
+            Severity::Warn => 3,
+            Severity::Error => 4,
+            Severity::Fatal => 5,
+        }
+    }
+
+    // Level asked for by `min_severity`. Unlike log types a filter must be a known severity,
+    // otherwise a typo would quietly filter like info.
+    pub fn parse_min(s: &str) -> Result<u8, String> {
+        match Severity::parse(s) {
+            Severity::Unknown(_) => Err(format!("Invalid min_severity {}", s)),
+            severity => Ok(severity.level()),
+        }
+    }
+
+    // Counted in `error_counts`
+    pub fn is_error(&self) -> bool {
+        *self == Severity::Error || *self == Severity::Fatal
+    }
+
+    // Parses comma separated list like `error,warn`
+    pub fn parse_list(s: &str) -> Vec<Severity> {
+        s.split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(Severity::parse)
+            .collect()
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Severity {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Severity {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Ok(Severity::parse(&s))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct SeverityCounts {
+    pub trace: u64,
+    pub debug: u64,
+    pub info: u64,
+    pub warn: u64,
+    pub error: u64,
+    pub fatal: u64,
+    pub unknown: u64,
+}
+
+impl SeverityCounts {
+    pub fn add(&mut self, severity: &Severity) {
+        match severity {
+            Severity::Trace => self.trace += 1,
+            Severity::Debug => self.debug += 1,
+            Severity::Info => self.info += 1,
+            Severity::Warn => self.warn += 1,
+            Severity::Error => self.error += 1,
+            Severity::Fatal => self.fatal += 1,
+            Severity::Unknown(_) => self.unknown += 1,
+        }
+    }
+}
+
+// `?min_severity=warn` accepted by plain read endpoints
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct SeverityQuery {
+    pub min_severity: Option<String>,
+}
+
+impl SeverityQuery {
+    pub fn validate(&self) -> Result<(), String> {
+        match &self.min_severity {
+            Some(min) => Severity::parse_min(min).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn matches(&self, severity: &Severity) -> bool {
+        match &self.min_severity {
+            Some(min) => severity.level() >= Severity::parse(min).level(),
+            None => true,
+        }
+    }
+}
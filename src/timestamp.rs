@@ -0,0 +1,406 @@
+// Instants stored by the server. Always kept in UTC.
+//
+// Serialized as RFC 3339 with milliseconds (`2020-10-18T06:37:11.123Z`). Read endpoints
+// accept `?tz=+05:45` (or `local`) to render in another offset and `?time_format=epoch_ms`
+// to render as epoch milliseconds instead; see `render`.
+//
+// Deserialization is tolerant so old snapshots and bots keep working: RFC 3339,
+// the old `"%F %r"` local time strings and epoch milliseconds are all accepted.
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cell::Cell;
+use std::convert::TryFrom;
+use std::fmt;
+use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset};
+
+// 0001-01-01T00:00:00.000Z and 9999-12-31T23:59:59.999Z
+const MIN_EPOCH_MILLIS: i64 = -62_135_596_800_000;
+const MAX_EPOCH_MILLIS: i64 = 253_402_300_799_999;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(OffsetDateTime);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Timestamp(OffsetDateTime::now_utc())
+    }
+
+    // None outside of years 0001 to 9999, time panics on those
+    pub fn from_epoch_millis(ms: i64) -> Option<Self> {
+        if !(MIN_EPOCH_MILLIS..=MAX_EPOCH_MILLIS).contains(&ms) {
+            return None;
+        }
+
+        let secs = OffsetDateTime::from_unix_timestamp(ms.div_euclid(1000));
+        Some(Timestamp(
+            secs + time::Duration::milliseconds(ms.rem_euclid(1000)),
+        ))
+    }
+
+    pub fn epoch_millis(&self) -> i64 {
+        self.0.unix_timestamp() * 1000 + i64::from(self.0.millisecond())
+    }
+
+    // Start of the `step_ms` long period self falls in, counted from the epoch
+    pub fn floor_millis(&self, step_ms: i64) -> Timestamp {
+        let ms = self.epoch_millis();
+        Timestamp(self.0 - time::Duration::milliseconds(ms.rem_euclid(step_ms)))
+    }
+
+    pub fn to_rfc3339(&self) -> String {
+        format_rfc3339(self.0, UtcOffset::UTC)
+    }
+
+    pub fn to_rfc3339_in(&self, offset: UtcOffset) -> String {
+        format_rfc3339(self.0, offset)
+    }
+
+    pub fn inner(&self) -> OffsetDateTime {
+        self.0
+    }
+
+    // Accepts RFC 3339 (any offset, optional fraction), epoch milliseconds and
+    // `"%F %r"`, `"%F %T"` without offset which are assumed to be local time.
+    pub fn parse(s: &str) -> Option<Timestamp> {
+        let s = s.trim();
+
+        if let Ok(ms) = s.parse::<i64>() {
+            return Timestamp::from_epoch_millis(ms);
+        }
+
+        if let Some(t) = parse_rfc3339(s) {
+            return Some(t);
+        }
+
+        let offset = local_offset();
+        ["%F %r", "%F %T", "%FT%T"]
+            .iter()
+            .find_map(|format| PrimitiveDateTime::parse(s, format).ok())
+            .map(|t| Timestamp(t.assume_offset(offset).to_offset(UtcOffset::UTC)))
+    }
+
+    pub fn add_secs(&self, secs: i64) -> Timestamp {
+        Timestamp(self.0 + time::Duration::seconds(secs))
+    }
+
+    // Seconds from `earlier` to self, negative if `earlier` is actually later
+    pub fn secs_since(&self, earlier: Timestamp) -> i64 {
+        (self.0 - earlier.0).whole_seconds()
+    }
+}
+
+// `+05:45`, `-03:00`, `Z`, `utc` or `local`
+pub fn parse_offset(s: &str) -> Option<UtcOffset> {
+    let s = s.trim();
+
+    match s.to_lowercase().as_str() {
+        "z" | "utc" => return Some(UtcOffset::UTC),
+        "local" => return Some(local_offset()),
+        _ => {}
+    }
+
+    let sign = match s.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+
+    let mut parts = s[1..].splitn(2, ':');
+    let hours: i32 = parts.next()?.parse().ok()?;
+    let minutes: i32 = parts.next().unwrap_or("0").parse().ok()?;
+
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+
+    Some(UtcOffset::seconds(sign * (hours * 3600 + minutes * 60)))
+}
+
+// Falls back to UTC when the system can't tell
+fn local_offset() -> UtcOffset {
+    UtcOffset::try_current_local_offset().unwrap_or(UtcOffset::UTC)
+}
+
+fn format_offset(offset: UtcOffset) -> String {
+    let seconds = offset.as_seconds();
+    if seconds == 0 {
+        return "Z".to_owned();
+    }
+
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let minutes = seconds.abs() / 60;
+    format!("{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+}
+
+fn format_rfc3339(t: OffsetDateTime, offset: UtcOffset) -> String {
+    let t = t.to_offset(offset);
+    format!(
+        "{}.{:03}{}",
+        t.format("%Y-%m-%dT%H:%M:%S"),
+        t.millisecond(),
+        format_offset(offset)
+    )
+}
+
+// time's own Rfc3339 parser gets non UTC offsets wrong so we split offset out ourselves
+fn parse_rfc3339(s: &str) -> Option<Timestamp> {
+    let (datetime, offset) = if s.ends_with('Z') || s.ends_with('z') {
+        (&s[..s.len() - 1], UtcOffset::UTC)
+    } else if s.len() > 6 {
+        // Offset may not start at a char boundary in garbage input, split_at would panic
+        let at = s.len() - 6;
+        (s.get(..at)?, parse_offset(s.get(at..)?)?)
+    } else {
+        return None;
+    };
+
+    let mut parts = datetime.splitn(2, '.');
+    let whole = parts.next()?.replace('t', "T");
+    let mut t = PrimitiveDateTime::parse(&whole, "%Y-%m-%dT%H:%M:%S").ok()?;
+
+    if let Some(fraction) = parts.next() {
+        if fraction.is_empty() || !fraction.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        // Only millisecond precision is kept
+        let millis = format!("{:0<3}", &fraction[..fraction.len().min(3)]);
+        t += time::Duration::milliseconds(millis.parse().ok()?);
+    }
+
+    Some(Timestamp(t.assume_offset(offset).to_offset(UtcOffset::UTC)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeFormat {
+    Rfc3339,
+    EpochMs,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Render {
+    offset: UtcOffset,
+    format: TimeFormat,
+}
+
+thread_local! {
+    static RENDER: Cell<Option<Render>> = const { Cell::new(None) };
+}
+
+// `?tz=..&time_format=..` accepted by read endpoints
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RenderQuery {
+    pub tz: Option<String>,
+    pub time_format: Option<String>,
+}
+
+impl RenderQuery {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(tz) = &self.tz {
+            parse_offset(tz).ok_or_else(|| format!("Invalid tz {}", tz))?;
+        }
+
+        match self.time_format.as_deref() {
+            None | Some("rfc3339") | Some("epoch_ms") => Ok(()),
+            Some(other) => Err(format!("Invalid time_format {}", other)),
+        }
+    }
+
+    // Serializes timestamps inside `f` as asked by query.
+    // Only affects current thread so `f` must serialize synchronously (like `warp::reply::json`).
+    pub fn render<T>(&self, f: impl FnOnce() -> T) -> T {
+        let render = Render {
+            offset: self
+                .tz
+                .as_deref()
+                .and_then(parse_offset)
+                .unwrap_or(UtcOffset::UTC),
+            format: match self.time_format.as_deref() {
+                Some("epoch_ms") => TimeFormat::EpochMs,
+                _ => TimeFormat::Rfc3339,
+            },
+        };
+
+        RENDER.with(|cell| {
+            let previous = cell.replace(Some(render));
+            let result = f();
+            cell.set(previous);
+            result
+        })
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_rfc3339())
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match RENDER.with(|cell| cell.get()) {
+            Some(Render {
+                format: TimeFormat::EpochMs,
+                ..
+            }) => serializer.serialize_i64(self.epoch_millis()),
+            Some(Render { offset, .. }) => serializer.serialize_str(&self.to_rfc3339_in(offset)),
+            None => serializer.serialize_str(&self.to_rfc3339()),
+        }
+    }
+}
+
+struct TimestampVisitor;
+
+impl<'de> Visitor<'de> for TimestampVisitor {
+    type Value = Timestamp;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("RFC 3339 time string or epoch milliseconds")
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Timestamp, E> {
+        Timestamp::from_epoch_millis(v).ok_or_else(|| E::custom(format!("invalid time {}", v)))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Timestamp, E> {
+        i64::try_from(v)
+            .ok()
+            .and_then(Timestamp::from_epoch_millis)
+            .ok_or_else(|| E::custom(format!("invalid time {}", v)))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Timestamp, E> {
+        // `as` saturates so NaN and huge values end up out of range
+        Timestamp::from_epoch_millis(v as i64)
+            .ok_or_else(|| E::custom(format!("invalid time {}", v)))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Timestamp, E> {
+        Timestamp::parse(v).ok_or_else(|| E::custom(format!("invalid time {}", v)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+}
+
+// For client supplied times: anything we can't understand becomes None instead of
+// rejecting whole request. Server still has `received_at` for such logs.
+pub fn deserialize_lenient<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Timestamp>, D::Error> {
+    let value = serde_json::Value::deserialize(deserializer)?;
+
+    Ok(match value {
+        serde_json::Value::String(s) => Timestamp::parse(&s),
+        serde_json::Value::Number(n) => n.as_i64().and_then(Timestamp::from_epoch_millis),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rfc3339(s: &str) -> Option<String> {
+        Timestamp::parse(s).map(|t| t.to_rfc3339())
+    }
+
+    #[test]
+    fn parses_rfc3339_with_offsets() {
+        let expected = Some("2020-10-18T06:37:11.123Z".to_owned());
+        assert_eq!(rfc3339("2020-10-18T06:37:11.123Z"), expected);
+        assert_eq!(rfc3339("2020-10-18t06:37:11.123z"), expected);
+        assert_eq!(rfc3339("2020-10-18T12:22:11.123+05:45"), expected);
+        assert_eq!(rfc3339("2020-10-18T03:37:11.123456-03:00"), expected);
+        assert_eq!(
+            rfc3339(" 2020-10-18T06:37:11.1Z "),
+            Some("2020-10-18T06:37:11.100Z".to_owned())
+        );
+        assert_eq!(
+            rfc3339("2020-10-18T06:37:11Z"),
+            Some("2020-10-18T06:37:11.000Z".to_owned())
+        );
+    }
+
+    #[test]
+    fn parses_epoch_millis_in_range_only() {
+        assert_eq!(
+            rfc3339("1603003031123"),
+            Some("2020-10-18T06:37:11.123Z".to_owned())
+        );
+        assert_eq!(rfc3339("-1"), Some("1969-12-31T23:59:59.999Z".to_owned()));
+        assert_eq!(
+            Timestamp::parse(&MAX_EPOCH_MILLIS.to_string()).map(|t| t.epoch_millis()),
+            Some(MAX_EPOCH_MILLIS)
+        );
+        assert_eq!(Timestamp::parse(&(MAX_EPOCH_MILLIS + 1).to_string()), None);
+        assert_eq!(Timestamp::parse(&(MIN_EPOCH_MILLIS - 1).to_string()), None);
+        assert_eq!(Timestamp::from_epoch_millis(i64::MAX), None);
+    }
+
+    #[test]
+    fn rejects_garbage_without_panicking() {
+        for s in [
+            "",
+            "Z",
+            "é",
+            "ééééa",
+            "éééééé",
+            "+05:45",
+            "2020-13-18T06:37:11Z",
+        ] {
+            assert_eq!(Timestamp::parse(s), None, "{:?}", s);
+        }
+
+        // Offset or fraction cut inside a multibyte char. `%FT%T` fallback ignores what follows
+        // seconds so these may parse, they just must not panic.
+        for s in [
+            "2020-10-18T06:37:11ééa",
+            "2020-10-18T06:37:11+05:4é",
+            "2020-10-18T06:37:11.1é3Z",
+        ] {
+            let _ = Timestamp::parse(s);
+        }
+    }
+
+    #[test]
+    fn parses_offsets() {
+        assert_eq!(
+            parse_offset("+05:45"),
+            Some(UtcOffset::seconds(5 * 3600 + 45 * 60))
+        );
+        assert_eq!(parse_offset("-03"), Some(UtcOffset::seconds(-3 * 3600)));
+        assert_eq!(parse_offset("UTC"), Some(UtcOffset::UTC));
+        assert_eq!(parse_offset("+24:00"), None);
+        assert_eq!(parse_offset("é"), None);
+        assert_eq!(parse_offset("+é"), None);
+        assert_eq!(parse_offset(""), None);
+    }
+
+    #[test]
+    fn renders_in_offset() {
+        let t = Timestamp::parse("2020-10-18T06:37:11.123Z").unwrap();
+        let offset = parse_offset("+05:45").unwrap();
+        assert_eq!(t.to_rfc3339_in(offset), "2020-10-18T12:22:11.123+05:45");
+        assert_eq!(Timestamp::parse(&t.to_rfc3339_in(offset)), Some(t));
+    }
+
+    #[test]
+    fn lenient_deserialization_drops_bad_times() {
+        #[derive(Deserialize)]
+        struct Log {
+            #[serde(default, deserialize_with = "deserialize_lenient")]
+            time: Option<Timestamp>,
+        }
+
+        let time = |json: &str| serde_json::from_str::<Log>(json).unwrap().time;
+        assert_eq!(time(r#"{"time": "ééééa"}"#), None);
+        assert_eq!(time(r#"{"time": true}"#), None);
+        assert_eq!(
+            time(r#"{"time": 1603003031123}"#).map(|t| t.epoch_millis()),
+            Some(1603003031123)
+        );
+    }
+}
@@ -1,17 +1,42 @@
 use tracing::info;
 use warp::Filter;
+use crate::alerts;
+use crate::api_calls;
+use crate::archive;
+use crate::audit;
+use crate::auth;
 use crate::cli;
-use crate::models;
+use crate::compression;
+use crate::liveness;
+use crate::log_buffer;
+use crate::persistence;
+use crate::retention;
 use crate::routes;
+use std::path::{Path, PathBuf};
+use tokio::time::Duration;
 
 
 pub fn run_server(shutdown_rx: Option<tokio::sync::mpsc::Receiver<()>>, from_service: bool) -> Result<(), String> {
     info!("Booting the server");
-    let config = cli::get_config(from_service);
+    let config = cli::get_config(from_service)?;
 
     let port = config.port;
 
-    let db = models::blank_db();
+    let data_dir = PathBuf::from(&config.data_dir);
+    // All config is set before loading so journal replay prunes and evicts (api call window,
+    // buffer sizes, memory budget..) the same way as when events first happened
+    retention::init(config.retention.clone());
+    archive::init(Path::new(&config.archive_dir), config.archive_max_files);
+    compression::init(config.compression_min_size, config.max_body_bytes);
+    auth::init(config.api_keys.clone());
+    api_calls::init(config.api_calls.clone());
+    liveness::init(config.liveness.clone());
+    log_buffer::init(config.log_buffers.clone());
+    crate::models::init_orphan_policy(config.orphan_logs);
+    let deliveries = alerts::init(config.alerts.clone());
+    let db = persistence::load_db(&data_dir, config.journal_fsync)?;
+    audit::init(&data_dir)
+        .map_err(|e| format!("Unable to open audit log in {:?}: {:?}", data_dir, e))?;
     let api = routes::all(db.clone());
 
     let fs = warp::fs::dir(config.html_path.to_owned());
@@ -20,27 +45,48 @@ pub fn run_server(shutdown_rx: Option<tokio::sync::mpsc::Receiver<()>>, from_ser
 
     info!("Listening on  http://127.0.0.1:{}", port);
     info!("Serving {} ", config.html_path);
+    info!("Storing data in {:?}", data_dir);
 
+    // When running directly on terminal ctrl-c is our stop signal
+    let mut shutdown_rx = match shutdown_rx {
+        Some(shutdown_rx) => shutdown_rx,
+        None => {
+            let (shutdown_tx, shutdown_rx) = tokio::sync::mpsc::channel(1);
+            ctrlc::set_handler(move || {
+                let _ = shutdown_tx.clone().try_send(());
+            })
+            .map_err(|_| "Error on setting ctrl-c handler".to_owned())?;
+            shutdown_rx
+        }
+    };
 
     let server = warp::serve(routes).run(([127, 0, 0, 1], port));
-    let clear_logs_future = crate::models::clear_database_periodically(db.clone());
+    let retention_future = retention::enforce_periodically(db.clone());
+    let budget_future = retention::enforce_budget(db.clone());
+    let alerts_future = alerts::run(db.clone(), deliveries);
+    let flush_future = persistence::flush_database_periodically(
+        db.clone(),
+        data_dir.clone(),
+        Duration::from_secs(config.flush_interval_secs),
+    );
 
     let mut rt = tokio::runtime::Runtime::new().map_err(|_| "Error on tokio runtime".to_owned())?;
-    let server_future = async { tokio::join!(server, clear_logs_future) };
-
-    if shutdown_rx.is_some() {
-        let mut shutdown_rx = shutdown_rx.unwrap();
-        let fut = async move {
-            tokio::select! {
-            _ = server_future => { println!("Warp Server has stopped");},
-            _ =  shutdown_rx.recv() => {println!("Windows server has told warp server to stop");}
-            }
-        };
-
-        rt.block_on(fut);
-    } else {
-        rt.block_on(server_future);
-    }
+    let server_future = async { tokio::join!(server, retention_future, budget_future, alerts_future, flush_future) };
+
+    let fut = async move {
+        tokio::select! {
+        _ = server_future => { println!("Warp Server has stopped");},
+        _ =  shutdown_rx.recv() => {println!("Server has been told to stop");}
+        }
+    };
+
+    rt.block_on(fut);
+
+    // Final flush so nothing since last periodic flush is lost
+    rt.block_on(persistence::flush_db(&db, &data_dir))
+        .map_err(|e| format!("Error on final flush {:?}", e))?;
+
+    info!("Database flushed to {:?}", data_dir);
 
     Ok(())
 }